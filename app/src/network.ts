@@ -4,18 +4,51 @@ import type {
   AnswerCommand,
   ByeMsg,
   CandidateCommand,
+  ErrorMsg,
+  HelloMsg,
   Command,
   CommandOfType,
   CommandTypes,
+  ConnectRequestMsg,
   GoFullscreenCommand,
+  IdentifiedMsg,
+  NoticeMsg,
   OfferCommand,
+  PairingCodeMsg,
   PayloadOfType,
 } from "./protocol";
-import { isByeMsg, isConnectedMsg, isWelcomeMsg, isXCommand } from "./protocol";
+import {
+  CAPABILITIES,
+  PROTOCOL_VERSION,
+  isByeMsg,
+  isConnectRequestMsg,
+  isConnectedMsg,
+  isErrorMsg,
+  isHelloMsg,
+  isIdentifiedMsg,
+  isNoticeMsg,
+  isPairingCodeMsg,
+  isWelcomeMsg,
+  isXCommand,
+} from "./protocol";
 import type { Observable } from "rxjs";
 
-const socket = webSocket<Command>(`wss://${location.host}/ws`);
-socket.next("subscribed" as any); // inital message to server, actually ignored
+// servers that require a token take it from a cookie or from the query
+const token = new URLSearchParams(location.search).get("token");
+const query = token ? `?token=${encodeURIComponent(token)}` : "";
+const socket = webSocket<Command>(`wss://${location.host}/ws${query}`);
+
+// tell the server what we speak, it hangs up with a bye if it can't
+socket.next(
+  { hello: { version: PROTOCOL_VERSION, capabilities: CAPABILITIES } } as any,
+);
+
+// take over the previous session of this tab, e.g. after a reload
+const RESUME_TOKEN = "cast-me-resume-token";
+const resumeToken = sessionStorage.getItem(RESUME_TOKEN);
+if (resumeToken) {
+  socket.next({ resume: resumeToken } as any);
+}
 
 type Fn<P, R> = (x: P) => R;
 const not = <T>(f: Fn<T, boolean>) => (x: T) => !f(x);
@@ -23,9 +56,22 @@ const not = <T>(f: Fn<T, boolean>) => (x: T) => !f(x);
 const isProtocolCmd = (command: any): command is Command =>
   typeof command === "object" &&
   ("welcome" in command ||
+    "hello" in command ||
     "connect" in command ||
+    "connectRequest" in command ||
+    "pairingCode" in command ||
     "connected" in command ||
-    "bye" in command);
+    "identified" in command ||
+    "bye" in command ||
+    "error" in command ||
+    "notice" in command);
+
+// what the server and we both speak
+export const helloReceived: Observable<HelloMsg["hello"]> = socket.pipe(
+  filter(isHelloMsg),
+  pluck("hello"),
+  first(),
+);
 
 // received bye
 export const byeReceived: Observable<ByeMsg["bye"]> = socket.pipe(
@@ -34,6 +80,33 @@ export const byeReceived: Observable<ByeMsg["bye"]> = socket.pipe(
   first(),
 );
 
+// something went wrong, e.g. connecting to an unknown peer
+export const errorReceived: Observable<ErrorMsg["error"]> = socket.pipe(
+  filter(isErrorMsg),
+  pluck("error"),
+);
+
+// announcements of the server, e.g. upcoming maintenance
+export const noticeReceived: Observable<NoticeMsg["notice"]> = socket.pipe(
+  filter(isNoticeMsg),
+  pluck("notice"),
+);
+
+// somebody asks to connect to you
+export const connectRequestReceived: Observable<
+  ConnectRequestMsg["connectRequest"]
+> = socket.pipe(
+  filter(isConnectRequestMsg),
+  pluck("connectRequest"),
+);
+
+// stands for your ID for a while
+export const pairingCodeReceived: Observable<PairingCodeMsg["pairingCode"]> =
+  socket.pipe(
+    filter(isPairingCodeMsg),
+    pluck("pairingCode"),
+  );
+
 // your peer's ID
 export const connectReceived: Observable<string> = socket.pipe(
   filter(isConnectedMsg),
@@ -41,10 +114,30 @@ export const connectReceived: Observable<string> = socket.pipe(
   first(),
 );
 
+// whom your peer authenticated as
+export const identifiedReceived: Observable<IdentifiedMsg["identified"]> =
+  socket.pipe(
+    filter(isIdentifiedMsg),
+    pluck("identified"),
+  );
+
+// ice servers advertised by the server
+export let iceServers: RTCIceServer[] = [];
+
 // your ID
 export const ownPeerId: Observable<string> = socket.pipe(
   filter(isWelcomeMsg),
-  map(({ welcome }) => welcome),
+  map(({ welcome }) => {
+    if (welcome.token) {
+      sessionStorage.setItem(RESUME_TOKEN, welcome.token);
+    }
+    iceServers = welcome.iceServers.map(({ urls, username, credential }) => ({
+      urls,
+      username,
+      credential,
+    }));
+    return welcome.id;
+  }),
 );
 
 export const sendAsRaw = (payload) => socket.next(payload);
@@ -1,6 +1,7 @@
 import {
   answers,
   candidates,
+  iceServers,
   offers,
   sendAsAnswer,
   sendAsCandidate,
@@ -17,7 +18,7 @@ export interface PeerInterface {
 }
 
 export function initP2P({ polite } = defaults): PeerInterface {
-  const pc = new RTCPeerConnection();
+  const pc = new RTCPeerConnection({ iceServers });
 
   const negotiate = async () => {
     const offer = await pc.createOffer();
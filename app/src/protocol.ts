@@ -47,20 +47,119 @@ export const isXCommand = <C>(tag: CommandTypes) => (cmd: unknown): cmd is C =>
 const isXMessage = <C>(tag: string) => (cmd: unknown): cmd is C =>
   typeof cmd === "object" && tag in cmd;
 
+export interface IceServer {
+  urls: string[];
+  username?: string;
+  credential?: string;
+  // unix timestamp after which the credential is rejected
+  expires?: number;
+}
+
+export type Capability =
+  | "resume"
+  | "rooms"
+  | "signaling"
+  | "binary"
+  | "pairingCodes";
+
+export const PROTOCOL_VERSION = 2;
+export const CAPABILITIES: Capability[] = [
+  "resume",
+  "signaling",
+  "pairingCodes",
+];
+
+// the server's answer to our hello
+export interface HelloMsg {
+  hello: {
+    version: number;
+    capabilities: Capability[];
+  };
+}
+
 export interface WelcomeMsg {
-  welcome: string;
+  welcome: {
+    id: string;
+    token?: string;
+    iceServers: IceServer[];
+    capabilities: Capability[];
+    version: number;
+  };
+}
+
+// somebody wants to connect, answer with `{ accept: from }` or `{ reject: from }`
+export interface ConnectRequestMsg {
+  connectRequest: {
+    from: string;
+    // whom they authenticated as
+    displayName?: string;
+  };
+}
+
+// answers `"requestCode"`, others can connect with `{ connect: { code } }` instead of our id, once
+export interface PairingCodeMsg {
+  pairingCode: {
+    code: string;
+    expiresInSecs: number;
+  };
 }
 
 export interface ConnectedMsg {
   connected: string;
 }
 
+// whom the correspondent authenticated as, follows `connected`
+export interface IdentifiedMsg {
+  identified: {
+    peer: string;
+    user: {
+      subject: string;
+      name?: string;
+    };
+  };
+}
+
 export interface ByeMsg {
   bye: {
     reason: string;
+    // the server is going away, come back after this many seconds
+    reconnectAfterSecs?: number;
+  };
+}
+
+export interface ErrorMsg {
+  error: {
+    code:
+      | "unknownPeer"
+      | "selfConnect"
+      | "peerBusy"
+      | "peerGone"
+      | "rejected"
+      | "timedOut"
+      | "noRequest"
+      | "rateLimited"
+      | "resumeFailed"
+      | "notNegotiated"
+      | "internal";
+    message: string;
+  };
+}
+
+// from the operators of the server
+export interface NoticeMsg {
+  notice: {
+    message: string;
   };
 }
 
+export const isHelloMsg = isXMessage<HelloMsg>("hello");
 export const isWelcomeMsg = isXMessage<WelcomeMsg>("welcome");
+export const isConnectRequestMsg = isXMessage<ConnectRequestMsg>(
+  "connectRequest",
+);
+export const isPairingCodeMsg = isXMessage<PairingCodeMsg>("pairingCode");
 export const isConnectedMsg = isXMessage<ConnectedMsg>("connected");
+export const isIdentifiedMsg = isXMessage<IdentifiedMsg>("identified");
 export const isByeMsg = isXMessage<ByeMsg>("bye");
+export const isErrorMsg = isXMessage<ErrorMsg>("error");
+export const isNoticeMsg = isXMessage<NoticeMsg>("notice");
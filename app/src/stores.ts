@@ -1,10 +1,17 @@
-import { writable } from "svelte/store";
+import { get, writable } from "svelte/store";
 import type { Writable } from "svelte/store";
 import {
   byeReceived,
   connectReceived,
+  connectRequestReceived,
+  errorReceived,
   goFullScreenReceived,
+  helloReceived,
+  identifiedReceived,
+  noticeReceived,
+  pairingCodeReceived,
   payloadMsg,
+  sendAsRaw,
 } from "./network";
 
 type CreateWritable<T> = (name: string) => Omit<Writable<T>, "update">;
@@ -23,9 +30,54 @@ export const createStore: CreateWritable<any> = (name) => {
 
 export const oppositePeerId = createStore("connected");
 export const oppositePeerLeftReason = createStore("oppositePeerLeftReason");
+export const oppositePeerUser = createStore("oppositePeerUser");
+
+export const lastError = createStore("lastError");
+
+export const serverNotice = createStore("serverNotice");
 
 export const iInitiatedTheCall = writable(false);
 
+// waiting for us to accept or reject, latest first
+export const connectRequests = writable([]);
+connectRequestReceived.subscribe((request) => {
+  console.debug("connect request", request);
+  connectRequests.update((requests) => [
+    request,
+    ...requests.filter(({ from }) => from !== request.from),
+  ]);
+});
+
+// whether the server hands out pairing codes
+export const pairingCodes = createStore("pairingCodes");
+
+export const requestCode = () => sendAsRaw("requestCode");
+
+// ours to hand out, until it expires
+export const pairingCode = createStore("pairingCode");
+pairingCodeReceived.subscribe(({ code, expiresInSecs }) => {
+  pairingCode.set(code);
+  setTimeout(() => {
+    // unless a newer one replaced it
+    if (get(pairingCode) !== code) {
+      return;
+    }
+    pairingCode.set(false);
+    // another one, for as long as nobody used ours
+    if (!get(oppositePeerId)) {
+      requestCode();
+    }
+  }, expiresInSecs * 1000);
+});
+
+// a code is easier to type than our id, so we start out with one
+helloReceived.subscribe(({ capabilities }) => {
+  if (capabilities.includes("pairingCodes")) {
+    pairingCodes.set(true);
+    requestCode();
+  }
+});
+
 export const messageHistory = (() => {
   const { subscribe, update } = writable([]);
   payloadMsg.subscribe((msg) => {
@@ -40,11 +92,31 @@ export const messageHistory = (() => {
 connectReceived.subscribe((correspondent) => {
   console.debug("connected to", correspondent);
   oppositePeerId.set(correspondent);
+  connectRequests.set([]);
+  pairingCode.set(false);
+});
+
+identifiedReceived.subscribe(({ user }) => {
+  console.debug("correspondent is", user);
+  oppositePeerUser.set(user.name ?? user.subject);
 });
 
-byeReceived.subscribe(({ reason }) => {
+byeReceived.subscribe(({ reason, reconnectAfterSecs }) => {
   console.debug("peer left", reason);
   oppositePeerLeftReason.set(reason);
+  if (reconnectAfterSecs !== undefined) {
+    setTimeout(() => location.reload(), reconnectAfterSecs * 1000);
+  }
+});
+
+errorReceived.subscribe(({ code, message }) => {
+  console.warn("server error", code, message);
+  lastError.set(message);
+});
+
+noticeReceived.subscribe(({ message }) => {
+  console.info("server notice", message);
+  serverNotice.set(message);
 });
 
 export const goFullScreen = writable(false);
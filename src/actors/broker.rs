@@ -1,17 +1,255 @@
 use hannibal::{prelude::*, Handler, WeakAddr};
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-use crate::PeerId;
+use crate::{
+    auth::Identity,
+    broker::{
+        BrokerStats, Inventory, PairingCodes, PeerInfo, PeerState, PeerSummary, PendingRequests,
+        SignalingBroker,
+    },
+    metrics::METRICS,
+    ConnectError, ConnectTo, IdGenerator, PairingCode, PeerId, ResumeToken, RoomId, WsProtocol,
+};
 
 use super::{
     peer::Peer,
-    protocol::{ConnectedFrom, Register, RequestConnectTo},
+    protocol::{
+        Admit, Broadcast, Buffer, Configure, ConnectedFrom, Disconnected, Forward, ForwardBinary,
+        Frame, HealthCheck, IssueCode, JoinRoom, Kick, Kicked, LeaveRoom, Register, RegisterToken,
+        RequestConnectTo, Respond, ResumeSession, Resumed, Shutdown, Stats, Stopped, TakeInventory,
+        ToRoom,
+    },
 };
 
+/// how many frames are kept for a suspended session
+const MAX_BUFFERED: usize = 256;
+
+/// A session whose websocket dropped, waiting to be resumed.
+struct Suspended {
+    correspondent: Option<(PeerId, WeakAddr<Peer>)>,
+    buffered: Vec<Frame>,
+    expires: Instant,
+}
+
 #[derive(Service, Default)]
 pub struct Broker {
     peers: HashMap<PeerId, WeakAddr<Peer>>,
+    paired: HashMap<PeerId, WeakAddr<Peer>>,
+    rooms: HashMap<RoomId, HashMap<PeerId, WeakAddr<Peer>>>,
+    tokens: HashMap<ResumeToken, PeerId>,
+    suspended: HashMap<PeerId, Suspended>,
+    resume_grace: Duration,
+    /// whom the paired peers are paired with, suspended sessions included
+    partners: HashMap<PeerId, PeerId>,
+    /// where and when the peers connected, for the admin api
+    infos: HashMap<PeerId, PeerInfo>,
+    /// connect requests waiting for an answer
+    pending: PendingRequests,
+    /// makes up the ids of new peers
+    ids: IdGenerator,
+    /// pairing codes that stand for peers
+    codes: PairingCodes,
+}
+
+impl Broker {
+    pub async fn configure(
+        ids: IdGenerator,
+        resume_grace: Duration,
+        connect_timeout: Duration,
+        code_ttl: Duration,
+    ) -> anyhow::Result<()> {
+        Broker::from_registry()
+            .await
+            .send(Configure {
+                ids,
+                resume_grace,
+                connect_timeout,
+                code_ttl,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The session is gone for good, let the correspondent know.
+    async fn expire(&mut self, id: PeerId, suspended: Suspended) {
+        self.tokens.retain(|_, owner| *owner != id);
+        self.partners.remove(&id);
+        self.infos.remove(&id);
+
+        if let Some(correspondent) = suspended.correspondent.and_then(|(_, addr)| addr.upgrade()) {
+            let disconnected = Disconnected {
+                id: id.clone(),
+                reason: String::from("disconnected"),
+            };
+            if let Err(error) = correspondent.send(disconnected).await {
+                tracing::warn!("failed to notify correspondent of {id}: {error}");
+            }
+        }
+    }
+
+    /// Deliver a frame to the members of a room, skipping the sender, returns how many it reached.
+    async fn deliver(
+        &self,
+        room: &RoomId,
+        from: &PeerId,
+        to: Option<&PeerId>,
+        frame: &Frame,
+    ) -> usize {
+        let Some(members) = self.rooms.get(room) else {
+            tracing::warn!("room {room} not found");
+            return 0;
+        };
+        let mut reached = 0;
+
+        for (id, addr) in members {
+            if id == from || to.is_some_and(|to| to != id) {
+                continue;
+            }
+            let Some(addr) = addr.upgrade() else {
+                continue;
+            };
+            let result = match frame.clone() {
+                Frame::Text(text) => addr.send(Forward(text)).await,
+                Frame::Binary(data) => addr.send(ForwardBinary(data)).await,
+            };
+            match result {
+                Ok(()) => reached += 1,
+                Err(error) => tracing::warn!("failed to deliver to {id} in {room}: {error}"),
+            }
+        }
+        reached
+    }
+
+    /// Pooled, paired and room members alike, suspended sessions have nobody to reach.
+    fn everyone(&self) -> impl Iterator<Item = (&PeerId, &WeakAddr<Peer>)> {
+        let members = self.rooms.values().flat_map(|members| members.iter());
+        self.peers.iter().chain(&self.paired).chain(members)
+    }
+
+    /// Peers cleaned out without a [`Stopped`] leave their info and partner behind.
+    fn forget_gone_peers(&mut self) {
+        let Self {
+            peers,
+            paired,
+            rooms,
+            suspended,
+            partners,
+            infos,
+            ..
+        } = self;
+        let known = |id: &PeerId| {
+            peers.contains_key(id)
+                || paired.contains_key(id)
+                || suspended.contains_key(id)
+                || rooms.values().any(|members| members.contains_key(id))
+        };
+        infos.retain(|id, _| known(id));
+        partners.retain(|id, _| paired.contains_key(id) || suspended.contains_key(id));
+    }
+
+    /// Whom `id` authenticated as.
+    fn user(&self, id: &PeerId) -> Option<Identity> {
+        self.infos.get(id).and_then(|info| info.user.clone())
+    }
+
+    /// A protocol message for a pooled peer, which may be gone by now.
+    async fn tell(&self, id: &PeerId, message: WsProtocol) {
+        let Some(addr) = self.peers.get(id).and_then(WeakAddr::upgrade) else {
+            tracing::debug!("{id} is no longer waiting for {message}");
+            return;
+        };
+        if let Err(error) = addr.send(Forward(message.to_string())).await {
+            tracing::debug!("failed to tell {id} {message}: {error}");
+        }
+    }
+
+    /// Whether `active` may ask `passive`, or be paired with it.
+    fn check_connectable(
+        &mut self,
+        active: &PeerId,
+        passive: &PeerId,
+    ) -> Result<(WeakAddr<Peer>, Addr<Peer>), ConnectError> {
+        if active == passive {
+            tracing::warn!("attempted to connect to self");
+            return Err(ConnectError::SelfConnect);
+        }
+
+        if self.is_busy(passive) {
+            tracing::warn!("passive peer already connected");
+            return Err(ConnectError::AlreadyPaired);
+        }
+
+        let Some(passive_addr) = self.peers.get(passive) else {
+            tracing::warn!("passive peer not found");
+            return Err(ConnectError::NotFound);
+        };
+
+        let Some(passive_addr) = passive_addr.upgrade() else {
+            tracing::warn!("passive peer not running");
+            self.peers.remove(passive);
+            return Err(ConnectError::NotRunning);
+        };
+
+        let Some(active_addr) = self.peers.get(active).filter(|other| !other.stopped()) else {
+            tracing::warn!("active peer not found");
+            return Err(ConnectError::NotRegistered);
+        };
+        Ok((active_addr.clone(), passive_addr))
+    }
+
+    /// Tell both peers about each other, once the request was accepted.
+    async fn link(&mut self, active: PeerId, passive: PeerId) -> Result<(), ConnectError> {
+        let (active_addr, passive_addr) = self.check_connectable(&active, &passive)?;
+        // before anybody is told, it may stop while the passive peer is
+        let Some(active_peer) = active_addr.upgrade() else {
+            tracing::warn!("failed to connect to peer: {} has stopped", active);
+            return Err(ConnectError::DeliveryFailed);
+        };
+
+        if let Err(err) = passive_addr
+            .send(ConnectedFrom {
+                id: active.clone(),
+                addr: active_addr.clone(),
+                user: self.user(&active),
+            })
+            .await
+        {
+            tracing::warn!("failed to connect to peer: {}", err);
+            Err(ConnectError::DeliveryFailed)
+        } else {
+            if let Err(err) = active_peer
+                .send(ConnectedFrom {
+                    id: passive.clone(),
+                    addr: passive_addr.downgrade(),
+                    user: self.user(&passive),
+                })
+                .await
+            {
+                tracing::warn!("failed to connect to peer: {}", err);
+            }
+
+            self.peers.remove(&active);
+            self.peers.remove(&passive);
+            self.partners.insert(active.clone(), passive.clone());
+            self.partners.insert(passive.clone(), active.clone());
+            self.paired.insert(active, active_addr);
+            self.paired.insert(passive, passive_addr.downgrade());
+            Ok(())
+        }
+    }
+
+    fn is_busy(&self, id: &PeerId) -> bool {
+        self.paired.contains_key(id) || self.rooms.values().any(|members| members.contains_key(id))
+    }
+
+    async fn announce(&self, room: &RoomId, from: &PeerId, message: WsProtocol) {
+        self.deliver(room, from, None, &Frame::Text(message.to_string()))
+            .await;
+    }
 }
 
 impl Actor for Broker {
@@ -32,14 +270,91 @@ struct GC;
 /// Remind yourself regularly to clean up.
 impl Handler<GC> for Broker {
     async fn handle(&mut self, _ctx: &mut Context<Self>, _: GC) {
+        let paired_before = self.paired.len();
+        self.paired.retain(|_, peer| !peer.stopped());
+        METRICS
+            .gc_removed
+            .add((paired_before - self.paired.len()) as u64);
+
+        let now = Instant::now();
+        let expired: Vec<PeerId> = self
+            .suspended
+            .iter()
+            .filter(|(_, suspended)| suspended.expires <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            if let Some(suspended) = self.suspended.remove(&id) {
+                tracing::debug!("session of {id} expired");
+                self.expire(id, suspended).await;
+            }
+        }
+
+        self.codes.expire();
+
+        for (from, to) in self.pending.take_expired() {
+            tracing::info!("{to} did not answer {from} in time");
+            METRICS
+                .connects
+                .record(&Err::<(), _>(ConnectError::TimedOut));
+            self.tell(&from, WsProtocol::from(ConnectError::TimedOut))
+                .await;
+        }
+
         if !self.peers.is_empty() {
             let len_before = self.peers.len();
             self.peers.retain(|_, peer| !peer.stopped());
             let len_after = self.peers.len();
             if len_after != len_before {
                 tracing::debug!("retained {len_after}/{len_before} peers");
+                METRICS.gc_removed.add((len_before - len_after) as u64);
             }
         }
+
+        let mut left = Vec::new();
+        for (room, members) in self.rooms.iter_mut() {
+            members.retain(|id, peer| {
+                if peer.stopped() {
+                    left.push((room.clone(), id.clone()));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.rooms.retain(|_, members| !members.is_empty());
+        self.forget_gone_peers();
+        for (room, peer) in left {
+            tracing::debug!("removed stale {peer} from {room}");
+            METRICS.gc_removed.inc();
+            self.announce(
+                &room,
+                &peer,
+                WsProtocol::PeerLeft {
+                    room: room.clone(),
+                    peer: peer.clone(),
+                },
+            )
+            .await;
+        }
+    }
+}
+
+/// Message from a new Peer that needs an id, `None` if every id is taken.
+impl Handler<Admit> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Admit) -> Option<PeerId> {
+        // suspended sessions may still be resumed under their ids
+        let id = self.ids.generate_unique(|id| {
+            self.suspended.contains_key(id) || self.everyone().any(|(other, _)| other == id)
+        });
+        let Some(id) = id else {
+            tracing::error!("no free peer id left, refusing new peer");
+            return None;
+        };
+        tracing::info!("admitting peer {id}");
+        self.infos.insert(id.clone(), msg.info);
+        self.peers.insert(id.clone(), msg.addr);
+        Some(id)
     }
 }
 
@@ -47,65 +362,626 @@ impl Handler<GC> for Broker {
 impl Handler<Register> for Broker {
     async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Register) {
         tracing::info!("registering peer {}", msg.id);
+        self.paired.remove(&msg.id);
+        self.partners.remove(&msg.id);
+        self.infos.insert(msg.id.clone(), msg.info);
         self.peers.insert(msg.id, msg.addr);
     }
 }
 
+impl Handler<RegisterToken> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: RegisterToken) {
+        self.tokens.insert(msg.token, msg.id);
+    }
+}
+
+impl Handler<Configure> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Configure) {
+        tracing::debug!(
+            "resume grace period {:?}, {:?} ids",
+            msg.resume_grace,
+            msg.ids
+        );
+        self.ids = msg.ids;
+        self.resume_grace = msg.resume_grace;
+        self.pending.set_timeout(msg.connect_timeout);
+        self.codes.set_ttl(msg.code_ttl);
+    }
+}
+
+/// Message from a Peer that it has stopped.
+impl Handler<Stopped> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Stopped) {
+        let Stopped {
+            id,
+            correspondent,
+            resumable,
+        } = msg;
+
+        self.peers.remove(&id);
+        self.paired.remove(&id);
+
+        let suspended = Suspended {
+            correspondent,
+            buffered: Vec::new(),
+            expires: Instant::now() + self.resume_grace,
+        };
+
+        if resumable && !self.resume_grace.is_zero() {
+            tracing::debug!("suspending session of {id} for {:?}", self.resume_grace);
+            self.suspended.insert(id, suspended);
+        } else {
+            self.expire(id, suspended).await;
+        }
+    }
+}
+
+/// Message from a Peer that wants to take over a suspended session.
+impl Handler<ResumeSession> for Broker {
+    async fn handle(
+        &mut self,
+        _ctx: &mut hannibal::Context<Self>,
+        msg: ResumeSession,
+    ) -> Option<Resumed> {
+        let ResumeSession {
+            token,
+            previous,
+            addr,
+        } = msg;
+
+        let id = self.tokens.get(&token).cloned()?;
+        if !self.suspended.contains_key(&id) {
+            tracing::warn!("session of {id} is still active");
+            return None;
+        }
+        if self.user(&id) != self.user(&previous) {
+            tracing::warn!("{previous} is not the user the session of {id} belongs to");
+            return None;
+        }
+        let suspended = self.suspended.remove(&id)?;
+        tracing::info!("{previous} resumes session of {id}");
+
+        self.tokens.remove(&token);
+        self.tokens.retain(|_, owner| *owner != previous);
+        self.peers.remove(&previous);
+        if let Some(info) = self.infos.remove(&previous) {
+            self.infos.insert(id.clone(), info);
+        }
+
+        let token = ResumeToken::default();
+        self.tokens.insert(token.clone(), id.clone());
+
+        // the correspondent may have resumed in the meantime, or may still be suspended itself
+        let correspondent = suspended.correspondent.and_then(|(other, other_addr)| {
+            if let Some(current) = self.paired.get(&other).filter(|addr| !addr.stopped()) {
+                Some((other, current.clone()))
+            } else if !other_addr.stopped() || self.suspended.contains_key(&other) {
+                Some((other, other_addr))
+            } else {
+                None
+            }
+        });
+
+        if let Some((other, other_addr)) = &correspondent {
+            self.paired.insert(id.clone(), addr.clone());
+            self.partners.insert(id.clone(), other.clone());
+            self.partners.insert(other.clone(), id.clone());
+            if let Some(other_addr) = other_addr.upgrade() {
+                let reconnected = ConnectedFrom {
+                    id: id.clone(),
+                    addr,
+                    user: self.user(&id),
+                };
+                if let Err(error) = other_addr.send(reconnected).await {
+                    tracing::warn!("failed to reconnect {id} with {other}: {error}");
+                }
+            }
+        } else {
+            self.peers.insert(id.clone(), addr);
+        }
+
+        let correspondent_user = correspondent
+            .as_ref()
+            .and_then(|(other, _)| self.user(other));
+        Some(Resumed {
+            id,
+            token,
+            correspondent,
+            correspondent_user,
+            buffered: suspended.buffered,
+        })
+    }
+}
+
+/// Message from a Peer whose correspondent is suspended.
+impl Handler<Buffer> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Buffer) {
+        let Some(suspended) = self.suspended.get_mut(&msg.to) else {
+            tracing::debug!("{} is not suspended, dropping frame", msg.to);
+            return;
+        };
+        if suspended.buffered.len() >= MAX_BUFFERED {
+            tracing::warn!("buffer of {} is full, dropping frame", msg.to);
+            return;
+        }
+        suspended.buffered.push(msg.frame);
+    }
+}
+
+/// Message from a Peer that wants a pairing code in place of its id.
+impl Handler<IssueCode> for Broker {
+    async fn handle(
+        &mut self,
+        _ctx: &mut hannibal::Context<Self>,
+        msg: IssueCode,
+    ) -> Option<(PairingCode, Duration)> {
+        let issued = self.codes.issue(&msg.id);
+        if issued.is_none() {
+            tracing::error!("no free pairing code left for {}", msg.id);
+        }
+        issued
+    }
+}
+
 /// Message from a Peer that it wants to connect to another peer.
 impl Handler<RequestConnectTo> for Broker {
     async fn handle(
         &mut self,
         _ctx: &mut hannibal::Context<Self>,
         msg: RequestConnectTo,
-    ) -> Result<WeakAddr<Peer>, String> {
-        let RequestConnectTo { active, passive } = msg;
+    ) -> Result<(), ConnectError> {
+        let RequestConnectTo {
+            active,
+            passive: to,
+        } = msg;
 
-        tracing::debug!("{active} is trying to connect to {passive}");
+        tracing::debug!("{active} is trying to connect to {to}");
+        let passive = self.codes.resolve(&to)?;
 
-        if active == passive {
-            tracing::warn!("attempted to connect to self");
-            return Err("attempted to connect to self".to_string());
+        let (_, passive_addr) = self.check_connectable(&active, &passive)?;
+        let request = WsProtocol::ConnectRequest {
+            from: active.clone(),
+            display_name: self
+                .user(&active)
+                .map(|user| user.display_name().to_owned()),
+        };
+        if let Err(err) = passive_addr.send(Forward(request.to_string())).await {
+            tracing::warn!("failed to ask peer: {}", err);
+            return Err(ConnectError::DeliveryFailed);
         }
+        tracing::info!("{active} asks {passive} to connect");
+        self.codes.use_up(&to);
+        self.pending.insert(active, passive);
+        Ok(())
+    }
+}
 
-        let active_addr = self.peers.remove(&active).filter(|other| !other.stopped());
+/// Message from a Peer that accepts or rejects a connect request.
+impl Handler<Respond> for Broker {
+    async fn handle(
+        &mut self,
+        _ctx: &mut hannibal::Context<Self>,
+        msg: Respond,
+    ) -> Result<(), ConnectError> {
+        let Respond { id, from, accept } = msg;
 
-        let Some(passive_addr) = self.peers.remove(&passive) else {
-            tracing::warn!("passive peer not found");
-            return Err("passive peer not found".to_string());
+        if !self.pending.take(&from, &id) {
+            return Err(ConnectError::NoRequest);
+        }
+
+        if !accept {
+            tracing::info!("{id} rejected {from}");
+            METRICS
+                .connects
+                .record(&Err::<(), _>(ConnectError::Rejected));
+            self.tell(&from, WsProtocol::from(ConnectError::Rejected))
+                .await;
+            return Ok(());
+        }
+
+        let linked = self.link(id.clone(), from.clone()).await;
+        METRICS.connects.record(&linked);
+        match linked {
+            Ok(()) => {
+                let mut requesters = self.pending.take_involving(&id);
+                requesters.extend(self.pending.take_involving(&from));
+                for requester in requesters {
+                    self.tell(&requester, WsProtocol::from(ConnectError::AlreadyPaired))
+                        .await;
+                }
+            }
+            Err(error) => {
+                tracing::warn!("failed to connect {from} to {id}: {error}");
+                self.tell(&from, WsProtocol::from(error)).await;
+            }
+        }
+        linked
+    }
+}
+
+/// Message from the metrics endpoint.
+impl Handler<Stats> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, _: Stats) -> BrokerStats {
+        let alive = |peers: &HashMap<PeerId, WeakAddr<Peer>>| {
+            peers.values().filter(|addr| !addr.stopped()).count()
         };
+        BrokerStats {
+            unpaired: alive(&self.peers),
+            pairs: alive(&self.paired) / 2,
+            room_members: self.rooms.values().map(alive).sum(),
+        }
+    }
+}
 
-        let Some(passive_addr) = passive_addr.upgrade() else {
-            tracing::warn!("passive peer not running");
-            return Err("passive peer not running".to_string());
+/// Message from the health endpoints, answering is all there is to it.
+impl Handler<HealthCheck> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, _: HealthCheck) {}
+}
+
+/// Message from the server that it is going away.
+impl Handler<Shutdown> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Shutdown) {
+        tracing::info!("shutting down, notifying peers");
+        for (id, addr) in self.everyone() {
+            let Some(addr) = addr.upgrade() else {
+                continue;
+            };
+            let shutdown = Shutdown {
+                reconnect_after: msg.reconnect_after,
+            };
+            if let Err(error) = addr.send(shutdown).await {
+                tracing::debug!("failed to shut down {id}: {error}");
+            }
+        }
+    }
+}
+
+/// Message from the admin api.
+impl Handler<TakeInventory> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, _: TakeInventory) -> Inventory {
+        let summary = |id: &PeerId, state| PeerSummary {
+            id: id.clone(),
+            state,
+            info: self.infos.get(id).cloned(),
+        };
+        let alive = |peers: &HashMap<PeerId, WeakAddr<Peer>>| {
+            peers
+                .iter()
+                .filter(|(_, addr)| !addr.stopped())
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>()
         };
 
-        let Some(active_addr) = active_addr else {
-            tracing::warn!("active peer not found");
-            return Err("active peer not found".to_string());
+        let mut inventory = Inventory::default();
+        for id in alive(&self.peers) {
+            inventory.peers.push(summary(&id, PeerState::Unpaired));
+        }
+        for id in alive(&self.paired) {
+            inventory.peers.push(summary(&id, PeerState::Paired));
+        }
+        for id in self.suspended.keys() {
+            inventory.peers.push(summary(id, PeerState::Suspended));
+        }
+        for (room, members) in &self.rooms {
+            let members = alive(members);
+            for id in &members {
+                inventory.peers.push(summary(id, PeerState::InRoom));
+            }
+            inventory.rooms.insert(room.clone(), members);
+        }
+        inventory.pairs = Inventory::pairs_of(&self.partners);
+        inventory
+    }
+}
+
+/// Message from the admin api, the peer says goodbye and stops.
+impl Handler<Kick> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Kick) -> bool {
+        let Some(addr) = self
+            .everyone()
+            .find(|(id, _)| **id == msg.id)
+            .and_then(|(_, addr)| addr.upgrade())
+        else {
+            return false;
         };
+        tracing::info!("kicking {}", msg.id);
+        if let Err(error) = addr.send(Kicked).await {
+            tracing::warn!("failed to kick {}: {error}", msg.id);
+            return false;
+        }
+        true
+    }
+}
 
-        if let Err(err) = passive_addr
-            .send(ConnectedFrom {
-                id: active,
-                addr: active_addr.clone(),
-            })
-            .await
-        {
-            tracing::warn!("failed to connect to peer: {}", err);
-            Err("failed to connect to peer".to_string())
-        } else {
-            if let Err(err) = active_addr
-                .upgrade()
-                .unwrap()
-                .send(ConnectedFrom {
-                    id: passive,
-                    addr: passive_addr.downgrade(),
-                })
-                .await
-            {
-                tracing::warn!("failed to connect to peer: {}", err);
+/// Message from the admin api.
+impl Handler<Broadcast> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: Broadcast) -> usize {
+        let notice = WsProtocol::Notice {
+            message: msg.message,
+        }
+        .to_string();
+        let mut reached = 0;
+        for (id, addr) in self.everyone() {
+            let Some(addr) = addr.upgrade() else {
+                continue;
+            };
+            match addr.send(Forward(notice.clone())).await {
+                Ok(()) => reached += 1,
+                Err(error) => tracing::debug!("failed to notify {id}: {error}"),
             }
-            Ok(passive_addr.downgrade())
         }
+        reached
     }
 }
+
+/// Message from a Peer that it wants to join a room.
+impl Handler<JoinRoom> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: JoinRoom) {
+        let JoinRoom { room, id, addr } = msg;
+        tracing::info!("{id} joins {room}");
+
+        // members of a room can't be connected to directly
+        self.peers.remove(&id);
+
+        let members = self.rooms.entry(room.clone()).or_default();
+        let others = members.keys().cloned().collect();
+        members.insert(id.clone(), addr.clone());
+
+        if let Some(addr) = addr.upgrade() {
+            let joined = WsProtocol::Joined {
+                room: room.clone(),
+                members: others,
+            };
+            if let Err(error) = addr.send(Forward(joined.to_string())).await {
+                tracing::warn!("failed to confirm join to {id}: {error}");
+            }
+        }
+
+        self.announce(
+            &room,
+            &id,
+            WsProtocol::PeerJoined {
+                room: room.clone(),
+                peer: id.clone(),
+            },
+        )
+        .await;
+    }
+}
+
+/// Message from a Peer that it is leaving a room.
+impl Handler<LeaveRoom> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: LeaveRoom) {
+        let LeaveRoom { room, id } = msg;
+        tracing::info!("{id} leaves {room}");
+
+        let Some(members) = self.rooms.get_mut(&room) else {
+            tracing::warn!("room {room} not found");
+            return;
+        };
+        if members.remove(&id).is_none() {
+            tracing::warn!("{id} is not a member of {room}");
+            return;
+        }
+        if members.is_empty() {
+            self.rooms.remove(&room);
+            return;
+        }
+
+        self.announce(
+            &room,
+            &id,
+            WsProtocol::PeerLeft {
+                room: room.clone(),
+                peer: id.clone(),
+            },
+        )
+        .await;
+    }
+}
+
+/// Message from a Peer to the other members of its room.
+impl Handler<ToRoom> for Broker {
+    async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: ToRoom) {
+        let ToRoom {
+            room,
+            from,
+            to,
+            frame,
+        } = msg;
+
+        if !self
+            .rooms
+            .get(&room)
+            .is_some_and(|members| members.contains_key(&from))
+        {
+            tracing::warn!("{from} is not a member of {room}");
+            return;
+        }
+
+        let reached = self.deliver(&room, &from, to.as_ref(), &frame).await;
+        for _ in 0..reached {
+            frame.count_forwarded();
+        }
+    }
+}
+
+impl SignalingBroker for Addr<Broker> {
+    type Peer = WeakAddr<Peer>;
+
+    async fn admit(&self, addr: WeakAddr<Peer>, info: PeerInfo) -> anyhow::Result<PeerId> {
+        self.call(Admit { addr, info })
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("no free peer id left"))
+    }
+
+    async fn register(
+        &self,
+        id: PeerId,
+        addr: WeakAddr<Peer>,
+        info: PeerInfo,
+    ) -> anyhow::Result<()> {
+        self.send(Register { id, addr, info }).await?;
+        Ok(())
+    }
+
+    async fn pairing_code(&self, id: PeerId) -> anyhow::Result<(PairingCode, Duration)> {
+        self.call(IssueCode { id })
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("no free pairing code left"))
+    }
+
+    async fn connect(&self, from: PeerId, to: ConnectTo) -> Result<(), ConnectError> {
+        let request = RequestConnectTo {
+            active: from,
+            passive: to,
+        };
+        let result = self.call(request).await.unwrap_or_else(|error| {
+            tracing::warn!("broker did not answer: {error}");
+            Err(ConnectError::DeliveryFailed)
+        });
+        // the outcome of a request is counted once it is answered
+        if result.is_err() {
+            METRICS.connects.record(&result);
+        }
+        result
+    }
+
+    async fn respond(&self, id: PeerId, from: PeerId, accept: bool) -> Result<(), ConnectError> {
+        self.call(Respond { id, from, accept })
+            .await
+            .unwrap_or_else(|error| {
+                tracing::warn!("broker did not answer: {error}");
+                Err(ConnectError::DeliveryFailed)
+            })
+    }
+
+    async fn join(&self, room: RoomId, id: PeerId, addr: WeakAddr<Peer>) -> anyhow::Result<()> {
+        self.send(JoinRoom { room, id, addr }).await?;
+        Ok(())
+    }
+
+    async fn leave(&self, room: RoomId, id: PeerId) -> anyhow::Result<()> {
+        self.send(LeaveRoom { room, id }).await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> anyhow::Result<BrokerStats> {
+        Ok(self.call(Stats).await?)
+    }
+
+    async fn health_check(&self) -> anyhow::Result<()> {
+        self.call(HealthCheck).await?;
+        Ok(())
+    }
+
+    async fn shutdown(&self, reconnect_after: Duration) -> anyhow::Result<()> {
+        self.send(Shutdown { reconnect_after }).await?;
+        Ok(())
+    }
+
+    async fn inventory(&self) -> anyhow::Result<Inventory> {
+        Ok(self.call(TakeInventory).await?)
+    }
+
+    async fn kick(&self, id: PeerId) -> anyhow::Result<bool> {
+        Ok(self.call(Kick { id }).await?)
+    }
+
+    async fn broadcast(&self, message: String) -> anyhow::Result<usize> {
+        Ok(self.call(Broadcast { message }).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ws::Message;
+    use futures::{channel::mpsc, SinkExt as _};
+    use tokio::{runtime::Runtime, sync::Mutex};
+
+    use std::{future::Future, sync::OnceLock};
+
+    use crate::{
+        broker::conformance::{self, conformance_tests, Conformance, TestPeer},
+        keepalive::Timeouts,
+        rate_limit::RateLimiter,
+        shutdown::Shutdown,
+        IceServers, RateLimitConfig, SessionConfig, ShutdownConfig,
+    };
+
+    use super::*;
+
+    /// Stops the peer along with its case, the broker is shared with the next one.
+    struct Running {
+        peer: Addr<Peer>,
+        _shutdown: Shutdown,
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            // stopped already by the case
+            let _ = self.peer.stop();
+        }
+    }
+
+    impl Conformance for Addr<Broker> {
+        async fn spawn_peer(&self) -> TestPeer<WeakAddr<Peer>> {
+            let (sender, sent) = mpsc::unbounded();
+            let (client, received) = mpsc::unbounded::<Result<Message, axum::Error>>();
+            let shutdown = Shutdown::new(Default::default(), ShutdownConfig::default());
+            let timeouts = Timeouts::from(&SessionConfig {
+                ping_interval_secs: 0,
+                ..Default::default()
+            });
+            let peer = Peer::new(
+                sender.sink_map_err(axum::Error::new),
+                IceServers::default(),
+                timeouts,
+                shutdown.sessions().open().unwrap(),
+                PeerInfo::new(([127, 0, 0, 1], 4711).into(), None),
+                RateLimiter::new(&RateLimitConfig::default()),
+            );
+            let addr = hannibal::build(peer).on_stream(received).spawn();
+            let running = Running {
+                peer: addr.clone(),
+                _shutdown: shutdown,
+            };
+            TestPeer::welcomed(addr.downgrade(), client, sent, running).await
+        }
+
+        async fn dead_peer(&self) -> WeakAddr<Peer> {
+            let test_peer = self.spawn_peer().await;
+            let peer = test_peer.peer.clone();
+            let mut addr = peer.upgrade().unwrap();
+            addr.stop().unwrap();
+            addr.await.unwrap();
+            assert!(peer.upgrade().is_none());
+            peer
+        }
+    }
+
+    /// The broker is a registry singleton, so every case shares it and its runtime, one at a time.
+    fn run<F: Future>(case: impl FnOnce(Addr<Broker>) -> F) {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        static ONE_AT_A_TIME: Mutex<()> = Mutex::const_new(());
+
+        let runtime = RUNTIME.get_or_init(|| {
+            let runtime = Runtime::new().unwrap();
+            runtime
+                .block_on(Broker::configure(
+                    IdGenerator::default(),
+                    Duration::from_secs(30),
+                    conformance::CONNECT_TIMEOUT,
+                    Duration::from_secs(120),
+                ))
+                .unwrap();
+            runtime
+        });
+        runtime.block_on(async {
+            let _one = ONE_AT_A_TIME.lock().await;
+            case(Broker::from_registry().await).await;
+        });
+    }
+
+    conformance_tests!(run);
+}
@@ -2,4 +2,5 @@ mod broker;
 mod peer;
 
 pub mod protocol;
+pub use broker::Broker;
 pub use peer::Peer;
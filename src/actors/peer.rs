@@ -1,14 +1,43 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures::{stream::SplitSink, SinkExt as _};
+use axum::{
+    body::Bytes,
+    extract::ws::{close_code, CloseFrame, Message},
+};
+use futures::{Sink, SinkExt as _};
 use hannibal::{prelude::*, Actor, StreamHandler, WeakAddr};
 
-use crate::{actors::protocol::Forward, peer_id::PeerId, ws_protocol::WsProtocol};
+use std::pin::Pin;
+
+use crate::{
+    actors::protocol::{Forward, ForwardBinary, Frame},
+    broker::{PeerInfo, SignalingBroker},
+    keepalive::{Check, Keepalive, Timeouts},
+    peer_id::PeerId,
+    rate_limit::RateLimiter,
+    shutdown::Session,
+    ws_protocol::{
+        unsupported_version, Capability, ErrorCode, WsProtocol, PROTOCOL_VERSION,
+        UNANNOUNCED_VERSION,
+    },
+    ConnectError, IceServers, ResumeToken, RoomId,
+};
+
+type WsSender = Pin<Box<dyn Sink<Message, Error = axum::Error> + Send + Sync>>;
 
-type WsSender = SplitSink<WebSocket, Message>;
+/// everything this backend supports
+const CAPABILITIES: &[Capability] = &[
+    Capability::Resume,
+    Capability::Rooms,
+    Capability::Signaling,
+    Capability::Binary,
+    Capability::PairingCodes,
+];
 
 use super::{
     broker::Broker,
-    protocol::{ConnectedFrom, RequestConnectTo},
+    protocol::{
+        Buffer, ConnectedFrom, Disconnected, Kicked, RegisterToken, ResumeSession, Resumed,
+        Shutdown, Stopped, ToRoom,
+    },
 };
 
 pub struct Peer {
@@ -16,14 +45,59 @@ pub struct Peer {
     /// sender to websocket
     pub ws_sender: WsSender,
     pub correspondent: Option<WeakAddr<Peer>>,
+    pub correspondent_id: Option<PeerId>,
+    pub room: Option<RoomId>,
+    /// allows a new websocket to take over this session
+    token: ResumeToken,
+    /// the client said goodbye, no need to keep the session around
+    leaving: bool,
+    /// negotiated in the client's hello, which has to come first
+    capabilities: Option<Vec<Capability>>,
+    /// advertised in the welcome
+    ice: IceServers,
+    /// pings the websocket and notices when it goes quiet
+    keepalive: Keepalive,
+    /// shutdown waits for this to be dropped
+    _session: Session,
+    /// where and when the websocket was opened
+    info: PeerInfo,
+    /// throttles connect requests
+    limiter: RateLimiter,
 }
 
 impl Peer {
-    pub fn new(sender: WsSender) -> Peer {
+    pub fn new(
+        sender: impl Sink<Message, Error = axum::Error> + Send + Sync + 'static,
+        ice: IceServers,
+        timeouts: Timeouts,
+        session: Session,
+        info: PeerInfo,
+        limiter: RateLimiter,
+    ) -> Peer {
         Self {
-            id: PeerId::default(),
-            ws_sender: sender,
+            id: PeerId::unassigned(),
+            ws_sender: Box::pin(sender),
             correspondent: None,
+            correspondent_id: None,
+            room: None,
+            token: ResumeToken::default(),
+            leaving: false,
+            capabilities: None,
+            ice,
+            keepalive: Keepalive::new(timeouts),
+            _session: session,
+            info,
+            limiter,
+        }
+    }
+
+    fn welcome(&self) -> WsProtocol {
+        WsProtocol::Welcome {
+            id: self.id.clone(),
+            token: Some(self.token.clone()),
+            ice_servers: self.ice.for_peer(&self.id),
+            capabilities: CAPABILITIES.to_vec(),
+            version: PROTOCOL_VERSION,
         }
     }
 
@@ -31,24 +105,362 @@ impl Peer {
         self.correspondent.as_ref().and_then(|o| o.upgrade())
     }
 
-    async fn handle_ws_message(&mut self, message: WsProtocol) -> anyhow::Result<()> {
-        if let WsProtocol::Connect(peer_id) = message {
-            tracing::debug!("connecting to {}", peer_id);
-            let active = self.id.clone();
-            let passive = peer_id.clone();
-            match Broker::from_registry()
+    /// Takes the id the broker hands out, before the welcome is sent.
+    async fn admit(&mut self, ctx: &mut hannibal::Context<Self>) -> anyhow::Result<()> {
+        let broker = Broker::from_registry().await;
+        self.id = broker.admit(ctx.weak_address(), self.info.clone()).await?;
+        broker
+            .send(RegisterToken {
+                id: self.id.clone(),
+                token: self.token.clone(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn register_at_broker(&self, ctx: &mut hannibal::Context<Self>) -> anyhow::Result<()> {
+        let broker = Broker::from_registry().await;
+        broker
+            .send(RegisterToken {
+                id: self.id.clone(),
+                token: self.token.clone(),
+            })
+            .await?;
+        broker
+            .register(self.id.clone(), ctx.weak_address(), self.info.clone())
+            .await
+    }
+
+    /// Frames for the correspondent are buffered by the broker while its session is suspended.
+    async fn forward(&self, frame: Frame) -> anyhow::Result<()> {
+        match (self.correspondent(), &self.correspondent_id) {
+            (Some(correspondent), _) => {
+                frame.count_forwarded();
+                match frame {
+                    Frame::Text(text) => correspondent.send(Forward(text)).await?,
+                    Frame::Binary(data) => correspondent.send(ForwardBinary(data)).await?,
+                }
+            }
+            (None, Some(to)) => {
+                frame.count_forwarded();
+                let buffer = Buffer {
+                    to: to.clone(),
+                    frame,
+                };
+                Broker::from_registry().await.send(buffer).await?
+            }
+            (None, None) => anyhow::bail!("no correspondent"),
+        }
+        Ok(())
+    }
+
+    /// Take over the identity and pairing of a dropped session.
+    async fn resume(&mut self, resumed: Resumed) -> anyhow::Result<()> {
+        let Resumed {
+            id,
+            token,
+            correspondent,
+            correspondent_user,
+            buffered,
+        } = resumed;
+        tracing::info!(peer = ?self.id, "resuming session of {id}");
+
+        self.id = id;
+        self.token = token;
+        self.ws_sender
+            .send(self.welcome().to_string().into())
+            .await?;
+
+        if let Some((other, other_addr)) = correspondent {
+            self.correspondent.replace(other_addr);
+            self.correspondent_id.replace(other.clone());
+            self.ws_sender
+                .send(WsProtocol::Connected(other.clone()).to_string().into())
+                .await?;
+            if let Some(user) = correspondent_user {
+                let identified = WsProtocol::Identified { peer: other, user };
+                self.ws_sender.send(identified.to_string().into()).await?;
+            }
+        }
+
+        for frame in buffered {
+            let message: Message = match frame {
+                Frame::Text(text) => text.into(),
+                Frame::Binary(data) => Message::Binary(data),
+            };
+            self.ws_sender.send(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_to_room(&self, to: Option<PeerId>, frame: Frame) -> anyhow::Result<()> {
+        if let Some(room) = self.room.clone() {
+            Broker::from_registry()
                 .await
-                .call(RequestConnectTo { active, passive })
-                .await?
-            {
-                Ok(correspondent) => {
-                    tracing::debug!("connected to {}", peer_id);
-                    self.correspondent.replace(correspondent);
+                .send(ToRoom {
+                    room,
+                    from: self.id.clone(),
+                    to,
+                    frame,
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Signaling is checked, stamped and routed to the addressed peer only.
+    async fn route_signal(&mut self, message: WsProtocol) -> anyhow::Result<()> {
+        if !self.negotiated(&message).await? {
+            return Ok(());
+        }
+        let (to, signal) = message.stamp_signal(&self.id)?;
+
+        if self.correspondent_id.is_some() {
+            if self.correspondent_id.as_ref() != Some(&to) {
+                anyhow::bail!("{to} is not the correspondent");
+            }
+            self.forward(Frame::Text(signal.to_string())).await?;
+        } else if self.room.is_some() {
+            self.send_to_room(Some(to), Frame::Text(signal.to_string()))
+                .await?;
+        } else {
+            anyhow::bail!("not connected to {to}");
+        }
+        Ok(())
+    }
+
+    /// The client has to say hello first, anything else comes from an older one.
+    async fn greet(
+        &mut self,
+        ctx: &mut hannibal::Context<Self>,
+        message: Message,
+    ) -> anyhow::Result<()> {
+        let hello = match &message {
+            Message::Text(text) => serde_json::from_str(text.as_str()).ok(),
+            _ => None,
+        };
+        match hello {
+            Some(WsProtocol::Hello {
+                version,
+                capabilities,
+            }) => self.hello(ctx, version, &capabilities).await,
+            _ => {
+                self.reject(ctx, unsupported_version(UNANNOUNCED_VERSION))
+                    .await
+            }
+        }
+    }
+
+    async fn hello(
+        &mut self,
+        ctx: &mut hannibal::Context<Self>,
+        version: u32,
+        offered: &[Capability],
+    ) -> anyhow::Result<()> {
+        match WsProtocol::answer_hello(version, offered, CAPABILITIES) {
+            Ok((hello, capabilities)) => {
+                self.capabilities = Some(capabilities);
+                self.ws_sender.send(hello.to_string().into()).await?;
+                Ok(())
+            }
+            Err(reason) => self.reject(ctx, reason).await,
+        }
+    }
+
+    fn speaks(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_some_and(|negotiated| negotiated.contains(&capability))
+    }
+
+    /// Whether the client negotiated what `message` needs, it is told if not.
+    async fn negotiated(&mut self, message: &WsProtocol) -> anyhow::Result<bool> {
+        match message.requires() {
+            Some(capability) if !self.speaks(capability) => {
+                tracing::debug!(peer = ?self.id, "{capability:?} was not negotiated");
+                self.ws_sender
+                    .send(
+                        WsProtocol::from(ErrorCode::NotNegotiated)
+                            .to_string()
+                            .into(),
+                    )
+                    .await?;
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Say why and hang up, there is no session worth keeping.
+    async fn reject(
+        &mut self,
+        ctx: &mut hannibal::Context<Self>,
+        reason: String,
+    ) -> anyhow::Result<()> {
+        tracing::info!(peer = ?self.id, "rejecting client: {reason}");
+        self.leaving = true;
+        self.ws_sender
+            .send(WsProtocol::bye(reason).to_string().into())
+            .await?;
+        self.ws_sender
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::PROTOCOL,
+                reason: "incompatible client".into(),
+            })))
+            .await?;
+        ctx.stop()?;
+        Ok(())
+    }
+
+    /// Close the websocket, the broker lets the correspondent know.
+    ///
+    /// A client that merely lost its connection can still resume within the grace period.
+    async fn time_out(
+        &mut self,
+        ctx: &mut hannibal::Context<Self>,
+        reason: &'static str,
+    ) -> anyhow::Result<()> {
+        tracing::info!(peer = ?self.id, "closing websocket: {reason}");
+        self.ws_sender
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: reason.into(),
+            })))
+            .await?;
+        ctx.stop()?;
+        Ok(())
+    }
+
+    /// Answer the connect request of `from`.
+    async fn respond(&mut self, from: PeerId, accept: bool) -> anyhow::Result<()> {
+        tracing::debug!(peer = ?self.id, accept, "answering {}", from);
+        if let Err(error) = Broker::from_registry()
+            .await
+            .respond(self.id.clone(), from, accept)
+            .await
+        {
+            self.ws_sender
+                .send(WsProtocol::from(error).to_string().into())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_ws_message(
+        &mut self,
+        ctx: &mut hannibal::Context<Self>,
+        message: WsProtocol,
+    ) -> anyhow::Result<()> {
+        if !self.negotiated(&message).await? {
+            return Ok(());
+        }
+        match (message, &self.room) {
+            (
+                WsProtocol::Hello {
+                    version,
+                    capabilities,
+                },
+                _,
+            ) => self.hello(ctx, version, &capabilities).await?,
+
+            (WsProtocol::Connect(peer_id), None) => {
+                tracing::debug!("connecting to {}", peer_id);
+                let ip = self.info.remote_addr.ip();
+                if let Err(limited) = self.limiter.connect(ip, &self.id) {
+                    self.ws_sender
+                        .send(WsProtocol::from(limited).to_string().into())
+                        .await?;
+                    return Ok(());
+                }
+                // once it accepts, the correspondent is handed over with `ConnectedFrom`
+                match Broker::from_registry()
+                    .await
+                    .connect(self.id.clone(), peer_id.clone())
+                    .await
+                {
+                    Ok(()) => tracing::debug!("asked {} to accept", peer_id),
+                    Err(error) => {
+                        tracing::warn!("failed to connect to {} ({})", peer_id, error);
+                        if error == ConnectError::NotFound {
+                            self.limiter.missed(ip);
+                        }
+                        self.ws_sender
+                            .send(WsProtocol::from(error).to_string().into())
+                            .await?;
+                    }
                 }
-                Err(error) => {
-                    tracing::warn!("failed to connect to {} ({})", peer_id, error);
+            }
+
+            (WsProtocol::RequestCode, None) => {
+                let answer = match Broker::from_registry()
+                    .await
+                    .pairing_code(self.id.clone())
+                    .await
+                {
+                    Ok((code, ttl)) => WsProtocol::PairingCode {
+                        code,
+                        expires_in_secs: ttl.as_secs(),
+                    },
+                    Err(error) => {
+                        tracing::warn!(peer = ?self.id, "got no pairing code: {error}");
+                        WsProtocol::from(ErrorCode::Internal)
+                    }
+                };
+                self.ws_sender.send(answer.to_string().into()).await?;
+            }
+
+            (WsProtocol::Accept(peer_id), None) => self.respond(peer_id, true).await?,
+            (WsProtocol::Reject(peer_id), None) => self.respond(peer_id, false).await?,
+
+            (WsProtocol::Resume(token), None) => {
+                let resume = ResumeSession {
+                    token,
+                    previous: self.id.clone(),
+                    addr: ctx.weak_address(),
+                };
+                match Broker::from_registry().await.call(resume).await? {
+                    Some(resumed) => self.resume(resumed).await?,
+                    None => {
+                        tracing::warn!(peer = ?self.id, "failed to resume session");
+                        self.ws_sender
+                            .send(WsProtocol::from(ErrorCode::ResumeFailed).to_string().into())
+                            .await?;
+                    }
+                }
+            }
+
+            (WsProtocol::Join(room), None) => {
+                tracing::debug!("joining {}", room);
+                self.room.replace(room.clone());
+                Broker::from_registry()
+                    .await
+                    .join(room, self.id.clone(), ctx.weak_address())
+                    .await?;
+            }
+
+            (WsProtocol::Leave, Some(_)) => {
+                if let Some(room) = self.room.take() {
+                    tracing::debug!("leaving {}", room);
+                    Broker::from_registry()
+                        .await
+                        .leave(room, self.id.clone())
+                        .await?;
+                    self.register_at_broker(ctx).await?;
                 }
             }
+
+            (WsProtocol::SendTo { to, payload }, Some(_)) => {
+                let received = WsProtocol::ReceivedFrom {
+                    from: self.id.clone(),
+                    payload,
+                };
+                self.send_to_room(Some(to), Frame::Text(received.to_string()))
+                    .await?;
+            }
+
+            (message, room) => {
+                tracing::warn!(peer = ?self.id, ?room, "unexpected message {message}");
+            }
         }
         Ok(())
     }
@@ -56,23 +468,37 @@ impl Peer {
 
 impl Actor for Peer {
     async fn started(&mut self, ctx: &mut hannibal::Context<Self>) -> hannibal::DynResult {
+        self.admit(ctx).await?;
         tracing::info!(peer = ?self.id, "peer started");
         self.ws_sender
-            .send(WsProtocol::Welcome(self.id.clone()).to_string().into())
-            .await?;
-        Broker::from_registry()
-            .await
-            .send(super::protocol::Register {
-                id: self.id.clone(),
-                addr: ctx.weak_address(),
-            })
+            .send(self.welcome().to_string().into())
             .await?;
+        if let Some(period) = self.keepalive.period() {
+            ctx.interval(CheckAlive, period);
+        }
 
         Ok(())
     }
 
     async fn stopped(&mut self, _: &mut hannibal::Context<Self>) {
         tracing::info!("peer stopped");
+        let stopped = Stopped {
+            id: self.id.clone(),
+            correspondent: self.correspondent_id.take().zip(self.correspondent.take()),
+            resumable: !self.leaving,
+        };
+        if let Err(error) = Broker::from_registry().await.send(stopped).await {
+            tracing::warn!(peer = ?self.id, "failed to notify broker ({error})");
+        }
+        if let Some(room) = self.room.take() {
+            let leave = Broker::from_registry()
+                .await
+                .leave(room, self.id.clone())
+                .await;
+            if let Err(error) = leave {
+                tracing::warn!(peer = ?self.id, "failed to leave room ({error})");
+            }
+        }
     }
 }
 
@@ -81,34 +507,84 @@ type WsStreamMessage = Result<Message, axum::Error>;
 /// Messages from the client
 impl StreamHandler<WsStreamMessage> for Peer {
     async fn handle(&mut self, ctx: &mut hannibal::Context<Self>, msg: WsStreamMessage) {
+        match &msg {
+            Ok(Message::Text(_) | Message::Binary(_)) => self.keepalive.active(),
+            Ok(_) => self.keepalive.heard(),
+            Err(_) => {}
+        }
         match msg {
+            Ok(message @ (Message::Text(_) | Message::Binary(_)))
+                if self.capabilities.is_none() =>
+            {
+                if let Err(error) = self.greet(ctx, message).await {
+                    tracing::warn!(peer = ?self.id, "error greeting client: {error}");
+                }
+            }
+
+            Ok(Message::Binary(_)) if !self.speaks(Capability::Binary) => {
+                let refused = WsProtocol::from(ErrorCode::NotNegotiated);
+                if let Err(error) = self.ws_sender.send(refused.to_string().into()).await {
+                    tracing::warn!(peer = ?self.id, "error refusing binary message: {error}");
+                }
+            }
+
             Ok(Message::Text(text)) => {
                 tracing::debug!("peer received text: {text}");
 
-                if let Some(correspondent) = self.correspondent() {
-                    if let Err(error) = correspondent.send(Forward(text.to_string())).await {
-                        tracing::warn!(peer = ?self.id, "error forwarding message: {error}");
+                let parsed = serde_json::from_str::<WsProtocol>(text.as_str());
+                match (parsed, self.correspondent_id.is_some()) {
+                    (Ok(message), _) if message.is_signal() => {
+                        if let Err(error) = self.route_signal(message).await {
+                            tracing::warn!(peer = ?self.id, "error routing signal: {error}");
+                        }
                     }
-                } else {
-                    match serde_json::from_str::<WsProtocol>(text.as_str()) {
-                        Ok(message) => {
-                            if let Err(err) = self.handle_ws_message(message).await {
-                                tracing::error!("error handling websocket message: {err}");
-                            }
+                    (_, true) => {
+                        if let Err(error) = self.forward(Frame::Text(text.to_string())).await {
+                            tracing::warn!(peer = ?self.id, "error forwarding message: {error}");
                         }
-                        Err(error) => {
-                            tracing::warn!(
-                                peer = ?self.id,
-                                ?text,
-                                "peer received invalid message: {error}"
-                            );
+                    }
+                    (Ok(message), false) => {
+                        if let Err(err) = self.handle_ws_message(ctx, message).await {
+                            tracing::error!("error handling websocket message: {err}");
+                        }
+                    }
+                    (Err(_), false) if self.room.is_some() => {
+                        if let Err(error) =
+                            self.send_to_room(None, Frame::Text(text.to_string())).await
+                        {
+                            tracing::warn!(peer = ?self.id, "error sending to room: {error}");
                         }
                     }
+                    (Err(error), false) => {
+                        tracing::warn!(
+                            peer = ?self.id,
+                            ?text,
+                            "peer received invalid message: {error}"
+                        );
+                    }
+                }
+            }
+
+            Ok(Message::Binary(data)) => {
+                tracing::debug!("peer received {} bytes", data.len());
+
+                if self.correspondent_id.is_some() {
+                    if let Err(error) = self.forward(Frame::Binary(data)).await {
+                        tracing::warn!(peer = ?self.id, "error forwarding binary message: {error}");
+                    }
+                } else if self.room.is_some() {
+                    if let Err(error) = self.send_to_room(None, Frame::Binary(data)).await {
+                        tracing::warn!(peer = ?self.id, "error sending to room: {error}");
+                    }
+                } else {
+                    tracing::trace!(peer = ?self.id, "no correspondent, ignoring binary message");
                 }
             }
 
-            Ok(Message::Close(_close_frame)) => {
+            Ok(Message::Close(close_frame)) => {
                 tracing::info!("websocket terminated by other side");
+                // a normal closure is a deliberate goodbye, anything else might come back
+                self.leaving = close_frame.is_some_and(|frame| frame.code == close_code::NORMAL);
                 if let Err(error) = ctx.stop() {
                     tracing::error!(peer = ?self.id, "error stopping peer actor: {error}");
                 }
@@ -122,18 +598,123 @@ impl StreamHandler<WsStreamMessage> for Peer {
     }
 }
 
+#[derive(Clone, Message)]
+struct CheckAlive;
+/// Remind yourself regularly to ping the client, or to give up on it.
+impl Handler<CheckAlive> for Peer {
+    async fn handle(&mut self, ctx: &mut hannibal::Context<Self>, _: CheckAlive) {
+        let result = match self.keepalive.check() {
+            Check::Alive => Ok(()),
+            Check::Ping => self
+                .ws_sender
+                .send(Message::Ping(Bytes::new()))
+                .await
+                .map_err(anyhow::Error::from),
+            Check::Unresponsive => self.time_out(ctx, "ping timeout").await,
+            Check::Idle => {
+                // the client is still there, no need to keep the session around
+                self.leaving = true;
+                let bye = WsProtocol::bye("idle");
+                match self.ws_sender.send(bye.to_string().into()).await {
+                    Ok(()) => self.time_out(ctx, "idle timeout").await,
+                    Err(error) => Err(error.into()),
+                }
+            }
+        };
+        if let Err(error) = result {
+            tracing::debug!(peer = ?self.id, "keepalive failed ({error})");
+            if let Err(error) = ctx.stop() {
+                tracing::error!(peer = ?self.id, "error stopping peer actor: {error}");
+            }
+        }
+    }
+}
+
 /// Message from Broker that the active peer has connected to you
 impl Handler<ConnectedFrom> for Peer {
     async fn handle(&mut self, _ctx: &mut hannibal::Context<Self>, msg: ConnectedFrom) {
         tracing::debug!(peer = ?self.id, "connected from {}", msg.id);
         self.correspondent.replace(msg.addr);
+        self.correspondent_id.replace(msg.id.clone());
         if let Err(error) = self
             .ws_sender
-            .send(WsProtocol::Connected(msg.id).to_string().into())
+            .send(WsProtocol::Connected(msg.id.clone()).to_string().into())
             .await
         {
             tracing::warn!("failed to send connected message to client ({error})");
         }
+        if let Some(user) = msg.user {
+            let identified = WsProtocol::Identified { peer: msg.id, user };
+            if let Err(error) = self.ws_sender.send(identified.to_string().into()).await {
+                tracing::warn!("failed to send identified message to client ({error})");
+            }
+        }
+    }
+}
+
+/// Message from the other peer that it has gone away
+impl Handler<Disconnected> for Peer {
+    async fn handle(&mut self, ctx: &mut hannibal::Context<Self>, msg: Disconnected) {
+        tracing::debug!(peer = ?self.id, "{} left ({})", msg.id, msg.reason);
+        self.correspondent = None;
+        self.correspondent_id = None;
+
+        // back to the pool before the client hears it can be connected to again
+        if let Err(error) = self.register_at_broker(ctx).await {
+            tracing::warn!(peer = ?self.id, "failed to re-register at broker ({error})");
+        }
+        if let Err(error) = self
+            .ws_sender
+            .send(WsProtocol::bye(msg.reason).to_string().into())
+            .await
+        {
+            tracing::warn!("failed to send bye message to client ({error})");
+        }
+    }
+}
+
+/// Message from Broker that the server is going away
+impl Handler<Shutdown> for Peer {
+    async fn handle(&mut self, ctx: &mut hannibal::Context<Self>, msg: Shutdown) {
+        // the session won't survive the server
+        self.leaving = true;
+        let bye = WsProtocol::shutting_down(msg.reconnect_after);
+        if let Err(error) = self.ws_sender.send(bye.to_string().into()).await {
+            tracing::debug!(peer = ?self.id, "failed to say goodbye ({error})");
+        }
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "server shutting down".into(),
+        }));
+        if let Err(error) = self.ws_sender.send(close).await {
+            tracing::debug!(peer = ?self.id, "failed to close websocket ({error})");
+        }
+        if let Err(error) = ctx.stop() {
+            tracing::error!(peer = ?self.id, "error stopping peer actor: {error}");
+        }
+    }
+}
+
+/// Message from Broker that an operator wants you gone
+impl Handler<Kicked> for Peer {
+    async fn handle(&mut self, ctx: &mut hannibal::Context<Self>, _: Kicked) {
+        tracing::info!(peer = ?self.id, "kicked");
+        // no coming back, the broker tells the correspondent we left
+        self.leaving = true;
+        let bye = WsProtocol::bye("kicked");
+        if let Err(error) = self.ws_sender.send(bye.to_string().into()).await {
+            tracing::debug!(peer = ?self.id, "failed to say goodbye ({error})");
+        }
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "kicked".into(),
+        }));
+        if let Err(error) = self.ws_sender.send(close).await {
+            tracing::debug!(peer = ?self.id, "failed to close websocket ({error})");
+        }
+        if let Err(error) = ctx.stop() {
+            tracing::error!(peer = ?self.id, "error stopping peer actor: {error}");
+        }
     }
 }
 
@@ -146,3 +727,13 @@ impl Handler<Forward> for Peer {
         }
     }
 }
+
+/// Binary message from the other peer for you to forward to the client
+impl Handler<ForwardBinary> for Peer {
+    async fn handle(&mut self, _ctx: &mut Context<Self>, ForwardBinary(data): ForwardBinary) {
+        tracing::debug!("forwarding {} bytes", data.len());
+        if let Err(error) = self.ws_sender.send(Message::Binary(data)).await {
+            tracing::warn!("error forwarding binary message: {error}");
+        }
+    }
+}
@@ -1,29 +1,198 @@
+use axum::body::Bytes;
 use hannibal::{prelude::*, WeakAddr};
 
-use crate::PeerId;
+use std::time::Duration;
+
+use crate::{
+    auth::Identity,
+    broker::{BrokerStats, Inventory, PeerInfo},
+    metrics::METRICS,
+    ConnectError, ConnectTo, IdGenerator, PairingCode, PeerId, ResumeToken, RoomId,
+};
 
 use super::Peer;
 
+/// 0. a new peer asks for an id nobody else goes by, `None` if there is no free one
+#[message(response = Option<PeerId>)]
+pub struct Admit {
+    pub addr: WeakAddr<Peer>,
+    pub info: PeerInfo,
+}
+
 /// 1. both peers register themselves with the broker
 #[message]
 pub struct Register {
     pub id: PeerId,
     pub addr: WeakAddr<Peer>,
+    pub info: PeerInfo,
 }
 
-/// 2. the active peer requests to connect to another peer
-#[message(response = Result<WeakAddr<Peer>, String>)]
+/// 1a. the token that allows a new websocket to take over the session of `id`
+#[message]
+pub struct RegisterToken {
+    pub id: PeerId,
+    pub token: ResumeToken,
+}
+
+/// 1b. a code that stands for `id`, `None` if there is no free one
+#[message(response = Option<(PairingCode, Duration)>)]
+pub struct IssueCode {
+    pub id: PeerId,
+}
+
+/// 2. the active peer requests to connect to another peer, which is asked to accept
+#[message(response = Result<(), ConnectError>)]
 pub struct RequestConnectTo {
     pub active: PeerId,
-    pub passive: PeerId,
+    pub passive: ConnectTo,
+}
+
+/// 2a. the passive peer accepts or rejects the request of `from`
+#[message(response = Result<(), ConnectError>)]
+pub struct Respond {
+    pub id: PeerId,
+    pub from: PeerId,
+    pub accept: bool,
 }
 
-/// 3. the passive peer receives a notification that it is connected to the active peer
+/// 3. both peers receive a notification that they are connected to each other
 #[message]
 pub struct ConnectedFrom {
     pub id: PeerId,
     pub addr: WeakAddr<Peer>,
+    /// whom `id` authenticated as
+    pub user: Option<Identity>,
+}
+
+/// 4. the remaining peer receives a notification that its correspondent has gone away
+#[message]
+pub struct Disconnected {
+    pub id: PeerId,
+    pub reason: String,
+}
+
+/// 5. a peer has stopped, the broker keeps its session around for a while if it is `resumable`
+#[message]
+pub struct Stopped {
+    pub id: PeerId,
+    pub correspondent: Option<(PeerId, WeakAddr<Peer>)>,
+    pub resumable: bool,
+}
+
+/// 6. a new peer presents the token of a stopped session to take it over
+#[message(response = Option<Resumed>)]
+pub struct ResumeSession {
+    pub token: ResumeToken,
+    /// the id the new peer was started with
+    pub previous: PeerId,
+    pub addr: WeakAddr<Peer>,
+}
+
+pub struct Resumed {
+    pub id: PeerId,
+    /// fresh token, the old one is used up
+    pub token: ResumeToken,
+    pub correspondent: Option<(PeerId, WeakAddr<Peer>)>,
+    /// whom the correspondent authenticated as
+    pub correspondent_user: Option<Identity>,
+    /// everything the correspondent sent while we were gone
+    pub buffered: Vec<Frame>,
+}
+
+/// a frame for a correspondent whose session is suspended
+#[message]
+pub struct Buffer {
+    pub to: PeerId,
+    pub frame: Frame,
+}
+
+#[message]
+pub struct Configure {
+    pub ids: IdGenerator,
+    pub resume_grace: Duration,
+    pub connect_timeout: Duration,
+    pub code_ttl: Duration,
 }
 
+/// what the broker holds right now
+#[message(response = BrokerStats)]
+pub struct Stats;
+
+/// round trip to check the broker is still alive
+#[message]
+pub struct HealthCheck;
+
+/// the server is going away, sent to the broker, which passes it on to every peer
+#[message]
+pub struct Shutdown {
+    pub reconnect_after: Duration,
+}
+
+/// everyone the broker holds, for the admin api
+#[message(response = Inventory)]
+pub struct TakeInventory;
+
+/// an operator wants `id` gone, answered with `false` if there is no such peer
+#[message(response = bool)]
+pub struct Kick {
+    pub id: PeerId,
+}
+
+/// sent to the peer by the broker, which passes [`Kick`] on
+#[message]
+pub struct Kicked;
+
+/// an operator notice for every peer, answered with how many were reached
+#[message(response = usize)]
+pub struct Broadcast {
+    pub message: String,
+}
+
+/// text frame from the correspondent
 #[message]
 pub struct Forward(pub String);
+
+/// binary frame from the correspondent
+#[message]
+pub struct ForwardBinary(pub Bytes);
+
+/// a websocket frame, as it is passed between peers
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Text(String),
+    Binary(Bytes),
+}
+
+impl Frame {
+    /// on its way from one client to another
+    pub fn count_forwarded(&self) {
+        match self {
+            Frame::Text(text) => METRICS.forwarded.text(text.len()),
+            Frame::Binary(data) => METRICS.forwarded.binary(data.len()),
+        }
+    }
+}
+
+/// a peer joins a room, the other members are notified
+#[message]
+pub struct JoinRoom {
+    pub room: RoomId,
+    pub id: PeerId,
+    pub addr: WeakAddr<Peer>,
+}
+
+/// a peer leaves a room, the other members are notified
+#[message]
+pub struct LeaveRoom {
+    pub room: RoomId,
+    pub id: PeerId,
+}
+
+/// a member sends a frame to everyone else in the room, or only to `to`
+#[message]
+pub struct ToRoom {
+    pub room: RoomId,
+    pub from: PeerId,
+    pub to: Option<PeerId>,
+    pub frame: Frame,
+}
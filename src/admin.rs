@@ -0,0 +1,204 @@
+//! Inspect and manage live sessions, for operators.
+//!
+//! Every route expects `server.admin.token` as `Authorization: Bearer <token>`.
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use crate::{broker::Inventory, AdminConfig, PeerId, RoomId};
+
+/// how long the broker gets to answer
+const BROKER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The configured token, compared in constant time.
+#[derive(Clone)]
+struct Token(hmac::Tag);
+
+impl Token {
+    /// what the token signs, any token signs it differently
+    const SIGNED: &'static [u8] = b"cast-me admin";
+
+    fn new(token: &str) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+        Self(hmac::sign(&key, Self::SIGNED))
+    }
+
+    fn matches(&self, presented: &str) -> bool {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, presented.as_bytes());
+        hmac::verify(&key, Self::SIGNED, self.0.as_ref()).is_ok()
+    }
+}
+
+/// `routes` behind the token, nothing at all if the admin api is turned off.
+pub fn protect<S>(routes: Router<S>, config: &AdminConfig) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    if !config.enabled {
+        return Router::new();
+    }
+    let token = Token::new(&config.token);
+    routes.route_layer(middleware::from_fn_with_state(token, authorize))
+}
+
+async fn authorize(State(token): State<Token>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if token.matches(presented) => next.run(request).await,
+        _ => {
+            tracing::warn!("unauthorized request to {}", request.uri().path());
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+            )
+                .into_response()
+        }
+    }
+}
+
+/// What the broker answered, 503 if it didn't.
+async fn ask<T>(answer: impl Future<Output = anyhow::Result<T>>) -> Result<T, Response> {
+    match tokio::time::timeout(BROKER_TIMEOUT, answer).await {
+        Ok(Ok(answer)) => Ok(answer),
+        Ok(Err(error)) => {
+            tracing::error!("broker failed to answer: {error}");
+            Err((StatusCode::SERVICE_UNAVAILABLE, "broker is gone").into_response())
+        }
+        Err(_) => {
+            tracing::error!("broker did not answer within {BROKER_TIMEOUT:?}");
+            Err((StatusCode::SERVICE_UNAVAILABLE, "broker is not responding").into_response())
+        }
+    }
+}
+
+/// Pairs and rooms, without the peers.
+#[derive(Serialize)]
+struct Sessions {
+    pairs: Vec<[PeerId; 2]>,
+    rooms: HashMap<RoomId, Vec<PeerId>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Notice {
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct Broadcasted {
+    reached: usize,
+}
+
+async fn peers(inventory: impl Future<Output = anyhow::Result<Inventory>>) -> Response {
+    match ask(inventory).await {
+        Ok(inventory) => Json(inventory.peers).into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn sessions(inventory: impl Future<Output = anyhow::Result<Inventory>>) -> Response {
+    match ask(inventory).await {
+        Ok(Inventory { pairs, rooms, .. }) => Json(Sessions { pairs, rooms }).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// 204 once the peer was told to go, 404 if there is no such peer.
+async fn kick(id: &PeerId, kicked: impl Future<Output = anyhow::Result<bool>>) -> Response {
+    match ask(kicked).await {
+        Ok(true) => {
+            tracing::info!("kicked {id}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "peer not found").into_response(),
+        Err(response) => response,
+    }
+}
+
+async fn broadcast(reached: impl Future<Output = anyhow::Result<usize>>) -> Response {
+    match ask(reached).await {
+        Ok(reached) => {
+            tracing::info!("broadcast a notice to {reached} peers");
+            Json(Broadcasted { reached }).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+pub mod actors {
+    use axum::{extract::Path, response::Response, Json};
+    use hannibal::Service as _;
+
+    use crate::{actors::Broker, broker::SignalingBroker as _, PeerId};
+
+    use super::Notice;
+
+    pub async fn peers() -> Response {
+        super::peers(async { Broker::from_registry().await.inventory().await }).await
+    }
+
+    pub async fn sessions() -> Response {
+        super::sessions(async { Broker::from_registry().await.inventory().await }).await
+    }
+
+    pub async fn kick(Path(id): Path<PeerId>) -> Response {
+        let kicked = async { Broker::from_registry().await.kick(id.clone()).await };
+        super::kick(&id, kicked).await
+    }
+
+    pub async fn broadcast(Json(notice): Json<Notice>) -> Response {
+        super::broadcast(async {
+            Broker::from_registry()
+                .await
+                .broadcast(notice.message)
+                .await
+        })
+        .await
+    }
+}
+
+pub mod basic {
+    use axum::{
+        extract::{Path, State},
+        response::Response,
+        Json,
+    };
+
+    use crate::{basic::Broker, broker::SignalingBroker as _, routes::Upgrades, PeerId};
+
+    use super::Notice;
+
+    pub async fn peers(State((broker, _)): State<(Broker, Upgrades)>) -> Response {
+        super::peers(broker.inventory()).await
+    }
+
+    pub async fn sessions(State((broker, _)): State<(Broker, Upgrades)>) -> Response {
+        super::sessions(broker.inventory()).await
+    }
+
+    pub async fn kick(
+        Path(id): Path<PeerId>,
+        State((broker, _)): State<(Broker, Upgrades)>,
+    ) -> Response {
+        super::kick(&id, broker.kick(id.clone())).await
+    }
+
+    pub async fn broadcast(
+        State((broker, _)): State<(Broker, Upgrades)>,
+        Json(notice): Json<Notice>,
+    ) -> Response {
+        super::broadcast(broker.broadcast(notice.message)).await
+    }
+}
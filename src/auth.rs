@@ -0,0 +1,585 @@
+//! Who is on the other end of a websocket, established before the upgrade.
+//!
+//! Clients present a JWT as `Authorization: Bearer <token>`, as a cookie or as a query parameter,
+//! browsers can't set headers on websockets.
+
+use anyhow::Context as _;
+use axum::http::{header, HeaderMap, Uri};
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine as _};
+use ring::{hmac, signature};
+use serde::{Deserialize, Serialize};
+
+use std::{fmt, sync::Arc};
+
+use crate::{clock, AuthConfig, JwtAlgorithm};
+
+/// The authenticated user behind a peer, shown to its correspondent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Identity {
+    /// the `sub` claim
+    pub subject: String,
+    /// the `name` claim
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Identity {
+    /// The name if there is one, the subject otherwise.
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.subject)
+    }
+}
+
+/// Reasons why an upgrade is rejected with 401.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    Malformed,
+    Algorithm,
+    Signature,
+    Expired,
+    NotYetValid,
+    Issuer,
+    Audience,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "no token"),
+            AuthError::Malformed => write!(f, "malformed token"),
+            AuthError::Algorithm => write!(f, "unexpected signature algorithm"),
+            AuthError::Signature => write!(f, "invalid signature"),
+            AuthError::Expired => write!(f, "token has expired"),
+            AuthError::NotYetValid => write!(f, "token is not valid yet"),
+            AuthError::Issuer => write!(f, "unexpected issuer"),
+            AuthError::Audience => write!(f, "unexpected audience"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Checks the credentials of an upgrade request.
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    fn authenticate(&self, headers: &HeaderMap, uri: &Uri) -> Result<Identity, AuthError>;
+}
+
+/// The configured authenticator, `None` if anybody may connect.
+pub async fn load(config: &AuthConfig) -> anyhow::Result<Option<Arc<dyn Authenticator>>> {
+    if !config.enabled {
+        return Ok(None);
+    }
+    let jwt = Jwt::load(config).await?;
+    tracing::info!(algorithm = ?config.algorithm, "websockets require a token");
+    Ok(Some(Arc::new(jwt)))
+}
+
+#[derive(Debug)]
+enum VerificationKey {
+    Hs256(hmac::Key),
+    /// DER encoded `RSAPublicKey`
+    Rs256(Vec<u8>),
+}
+
+impl VerificationKey {
+    fn verify(&self, signed: &[u8], signature: &[u8]) -> Result<(), AuthError> {
+        let verified = match self {
+            VerificationKey::Hs256(key) => hmac::verify(key, signed, signature),
+            VerificationKey::Rs256(key) => {
+                signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, key)
+                    .verify(signed, signature)
+            }
+        };
+        verified.map_err(|_| AuthError::Signature)
+    }
+}
+
+/// Signed JWTs, see [`AuthConfig`].
+#[derive(Debug)]
+struct Jwt {
+    algorithm: JwtAlgorithm,
+    key: VerificationKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    cookie: String,
+    query_param: String,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    name: Option<String>,
+    exp: u64,
+    #[serde(default)]
+    nbf: Option<u64>,
+    #[serde(default)]
+    iss: Option<String>,
+    #[serde(default)]
+    aud: Option<Audience>,
+}
+
+impl Jwt {
+    async fn load(config: &AuthConfig) -> anyhow::Result<Self> {
+        let key = match config.algorithm {
+            JwtAlgorithm::Hs256 => {
+                VerificationKey::Hs256(hmac::Key::new(hmac::HMAC_SHA256, config.secret.as_bytes()))
+            }
+            JwtAlgorithm::Rs256 => {
+                let path = config
+                    .public_key
+                    .as_ref()
+                    .context("server.auth.public_key: required by RS256")?;
+                let pem = tokio::fs::read(path)
+                    .await
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                let spki = rustls_pemfile::public_keys(&mut pem.as_slice())
+                    .next()
+                    .with_context(|| format!("no public key in {}", path.display()))?
+                    .with_context(|| format!("invalid public key in {}", path.display()))?;
+                let key = rsa_public_key(spki.as_ref())
+                    .with_context(|| format!("no rsa public key in {}", path.display()))?;
+                VerificationKey::Rs256(key.to_vec())
+            }
+        };
+        Ok(Self {
+            algorithm: config.algorithm,
+            key,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway: config.leeway_secs,
+            cookie: config.cookie.clone(),
+            query_param: config.query_param.clone(),
+        })
+    }
+
+    /// From the authorization header, the cookie or the query, in that order.
+    fn presented<'a>(&self, headers: &'a HeaderMap, uri: &'a Uri) -> Option<&'a str> {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let cookie = || {
+            headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(name, _)| *name == self.cookie)
+                .map(|(_, value)| value)
+        };
+        // tokens are url safe, there is nothing to decode
+        let query = || {
+            uri.query()?
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| *name == self.query_param)
+                .map(|(_, value)| value)
+        };
+        bearer.or_else(cookie).or_else(query)
+    }
+
+    fn verify(&self, token: &str) -> Result<Identity, AuthError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(claims), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(AuthError::Malformed);
+        };
+        let signed = &token[..header.len() + 1 + claims.len()];
+
+        let header: Header = decode(header)?;
+        if header.alg != self.algorithm.name() {
+            return Err(AuthError::Algorithm);
+        }
+        let signature = BASE64_URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| AuthError::Malformed)?;
+        self.key.verify(signed.as_bytes(), &signature)?;
+
+        let claims: Claims = decode(claims)?;
+        let now = clock::unix_time();
+        if claims.exp.saturating_add(self.leeway) <= now {
+            return Err(AuthError::Expired);
+        }
+        if claims
+            .nbf
+            .is_some_and(|nbf| nbf > now.saturating_add(self.leeway))
+        {
+            return Err(AuthError::NotYetValid);
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.iss.as_ref() != Some(issuer) {
+                return Err(AuthError::Issuer);
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match &claims.aud {
+                Some(Audience::One(aud)) => aud == audience,
+                Some(Audience::Many(auds)) => auds.contains(audience),
+                None => false,
+            };
+            if !matches {
+                return Err(AuthError::Audience);
+            }
+        }
+
+        Ok(Identity {
+            subject: claims.sub,
+            name: claims.name,
+        })
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate(&self, headers: &HeaderMap, uri: &Uri) -> Result<Identity, AuthError> {
+        let token = self.presented(headers, uri).ok_or(AuthError::Missing)?;
+        self.verify(token)
+    }
+}
+
+fn decode<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, AuthError> {
+    let json = BASE64_URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AuthError::Malformed)?;
+    serde_json::from_slice(&json).map_err(|_| AuthError::Malformed)
+}
+
+/// The `RSAPublicKey` inside a `SubjectPublicKeyInfo`, which is what ring expects.
+fn rsa_public_key(spki: &[u8]) -> Option<&[u8]> {
+    let (spki, _) = der(0x30, spki)?;
+    let (_algorithm, rest) = der(0x30, spki)?;
+    let (bits, _) = der(0x03, rest)?;
+    // no unused bits
+    bits.strip_prefix(&[0])
+}
+
+/// Contents of the DER element tagged `tag` at the start of `input`, and what follows it.
+fn der(tag: u8, input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&actual, input) = input.split_first()?;
+    if actual != tag {
+        return None;
+    }
+    let (&first, input) = input.split_first()?;
+    let (len, input) = if first < 0x80 {
+        (usize::from(first), input)
+    } else {
+        let octets = usize::from(first & 0x7f);
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let (len, input) = input.split_at(octets);
+        let len = len.iter().fold(0, |len, &b| len << 8 | usize::from(b));
+        (len, input)
+    };
+    (input.len() >= len).then(|| input.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::rand::SystemRandom;
+    use serde_json::{json, Value};
+
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const SECRET: &str = "not so secret";
+
+    fn config(algorithm: JwtAlgorithm) -> AuthConfig {
+        AuthConfig {
+            enabled: true,
+            algorithm,
+            secret: String::from(SECRET),
+            public_key: Some(PathBuf::from("testcerts/public.pem")),
+            ..Default::default()
+        }
+    }
+
+    async fn jwt(config: AuthConfig) -> Jwt {
+        Jwt::load(&config).await.unwrap()
+    }
+
+    fn encode(part: Value) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(part.to_string())
+    }
+
+    /// A token with `alg` in its header, signed by `sign`.
+    fn token(alg: &str, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let signed = format!(
+            "{}.{}",
+            encode(json!({ "alg": alg, "typ": "JWT" })),
+            encode(claims)
+        );
+        let signature = BASE64_URL_SAFE_NO_PAD.encode(sign(signed.as_bytes()));
+        format!("{}.{}", signed, signature)
+    }
+
+    fn hmac_with(secret: &str) -> impl Fn(&[u8]) -> Vec<u8> + '_ {
+        move |signed| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            hmac::sign(&key, signed).as_ref().to_vec()
+        }
+    }
+
+    /// signed with the private half of `testcerts/public.pem`
+    fn rsa(signed: &[u8]) -> Vec<u8> {
+        let pem = include_bytes!("../testcerts/key.pem");
+        let key = rustls_pemfile::private_key(&mut pem.as_slice())
+            .unwrap()
+            .unwrap();
+        let key = signature::RsaKeyPair::from_pkcs8(key.secret_der()).unwrap();
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &signature::RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            signed,
+            &mut signature,
+        )
+        .unwrap();
+        signature
+    }
+
+    fn hs256(claims: Value) -> String {
+        token("HS256", claims, hmac_with(SECRET))
+    }
+
+    fn rs256(claims: Value) -> String {
+        token("RS256", claims, rsa)
+    }
+
+    /// `sub` and `exp` plus `more`
+    fn claims(more: Value) -> Value {
+        let mut claims = json!({ "sub": "alice", "exp": clock::unix_time() + 60 });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(more.as_object().unwrap().clone());
+        claims
+    }
+
+    fn alice() -> Identity {
+        Identity {
+            subject: String::from("alice"),
+            name: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn valid() {
+        let hs = jwt(config(JwtAlgorithm::Hs256)).await;
+        let identity = hs.verify(&hs256(claims(json!({ "name": "Alice" }))));
+        assert_eq!(
+            identity,
+            Ok(Identity {
+                subject: String::from("alice"),
+                name: Some(String::from("Alice")),
+            })
+        );
+
+        let rs = jwt(config(JwtAlgorithm::Rs256)).await;
+        assert_eq!(rs.verify(&rs256(claims(json!({})))), Ok(alice()));
+    }
+
+    #[tokio::test]
+    async fn wrong_algorithm() {
+        let hs = jwt(config(JwtAlgorithm::Hs256)).await;
+        let rs = jwt(config(JwtAlgorithm::Rs256)).await;
+        let unsigned = token("none", claims(json!({})), |_| Vec::new());
+        assert_eq!(hs.verify(&unsigned), Err(AuthError::Algorithm));
+        assert_eq!(rs.verify(&unsigned), Err(AuthError::Algorithm));
+        assert_eq!(
+            hs.verify(&rs256(claims(json!({})))),
+            Err(AuthError::Algorithm)
+        );
+
+        // the public key is no HMAC secret
+        let public = std::fs::read_to_string("testcerts/public.pem").unwrap();
+        let forged = token("HS256", claims(json!({})), hmac_with(&public));
+        assert_eq!(rs.verify(&forged), Err(AuthError::Algorithm));
+    }
+
+    #[tokio::test]
+    async fn bad_signature() {
+        let hs = jwt(config(JwtAlgorithm::Hs256)).await;
+        let rs = jwt(config(JwtAlgorithm::Rs256)).await;
+        let other_secret = token("HS256", claims(json!({})), hmac_with("guessed"));
+        assert_eq!(hs.verify(&other_secret), Err(AuthError::Signature));
+
+        for (jwt, token) in [
+            (hs, hs256(claims(json!({})))),
+            (rs, rs256(claims(json!({})))),
+        ] {
+            let (signed, signature) = token.rsplit_once('.').unwrap();
+            let (header, _) = signed.split_once('.').unwrap();
+            let escalated = encode(claims(json!({ "sub": "root" })));
+            let tampered = format!("{}.{}.{}", header, escalated, signature);
+            assert_eq!(jwt.verify(&tampered), Err(AuthError::Signature));
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed() {
+        let jwt = jwt(config(JwtAlgorithm::Hs256)).await;
+        let token = hs256(claims(json!({})));
+        assert_eq!(jwt.verify("two.parts"), Err(AuthError::Malformed));
+        assert_eq!(
+            jwt.verify(&format!("{}.more", token)),
+            Err(AuthError::Malformed)
+        );
+        assert_eq!(jwt.verify("not base64!.x.y"), Err(AuthError::Malformed));
+        let no_subject = hs256(json!({ "exp": clock::unix_time() + 60 }));
+        assert_eq!(jwt.verify(&no_subject), Err(AuthError::Malformed));
+    }
+
+    #[tokio::test]
+    async fn expiry_with_leeway() {
+        let jwt = jwt(config(JwtAlgorithm::Hs256)).await;
+        let now = clock::unix_time();
+        let at = |exp: u64, nbf: u64| {
+            jwt.verify(&hs256(claims(json!({ "exp": exp, "nbf": nbf }))))
+                .map(|_| ())
+        };
+
+        assert_eq!(at(now - 10, now - 100), Ok(()));
+        assert_eq!(at(now - 30, now - 100), Err(AuthError::Expired));
+        assert_eq!(at(u64::MAX, now - 100), Ok(()));
+        assert_eq!(at(now + 60, now + 10), Ok(()));
+        assert_eq!(at(now + 60, now + 31), Err(AuthError::NotYetValid));
+    }
+
+    #[tokio::test]
+    async fn issuer() {
+        let jwt = jwt(AuthConfig {
+            issuer: Some(String::from("https://id.example")),
+            ..config(JwtAlgorithm::Hs256)
+        })
+        .await;
+        let from = |iss: Value| jwt.verify(&hs256(claims(json!({ "iss": iss }))));
+        assert_eq!(from(json!("https://id.example")), Ok(alice()));
+        assert_eq!(from(json!("https://evil.example")), Err(AuthError::Issuer));
+        assert_eq!(from(json!(null)), Err(AuthError::Issuer));
+    }
+
+    #[tokio::test]
+    async fn audience() {
+        let jwt = jwt(AuthConfig {
+            audience: Some(String::from("cast-me")),
+            ..config(JwtAlgorithm::Hs256)
+        })
+        .await;
+        let to = |aud: Value| jwt.verify(&hs256(claims(json!({ "aud": aud }))));
+        assert_eq!(to(json!("cast-me")), Ok(alice()));
+        assert_eq!(to(json!(["mail", "cast-me"])), Ok(alice()));
+        assert_eq!(to(json!("mail")), Err(AuthError::Audience));
+        assert_eq!(to(json!(["mail"])), Err(AuthError::Audience));
+        assert_eq!(to(json!(null)), Err(AuthError::Audience));
+    }
+
+    #[tokio::test]
+    async fn presented() {
+        let jwt = jwt(config(JwtAlgorithm::Hs256)).await;
+        let valid = hs256(claims(json!({})));
+        let forged = token("HS256", claims(json!({})), hmac_with("guessed"));
+        let authenticate = |pairs: Vec<(header::HeaderName, String)>, uri: String| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(name, value.parse().unwrap());
+            }
+            jwt.authenticate(&headers, &uri.parse().unwrap())
+        };
+        let bearer = (header::AUTHORIZATION, format!("Bearer {}", valid));
+        let cookie = (
+            header::COOKIE,
+            format!("theme=dark; cast_me_token={}; lang=en", valid),
+        );
+        let plain = String::from("/ws");
+
+        assert_eq!(
+            authenticate(vec![bearer.clone()], plain.clone()),
+            Ok(alice())
+        );
+        assert_eq!(
+            authenticate(vec![cookie.clone()], plain.clone()),
+            Ok(alice())
+        );
+        let query = format!("/ws?room=lobby&token={}", valid);
+        assert_eq!(authenticate(vec![], query), Ok(alice()));
+
+        // the header wins over the cookie, the cookie over the query
+        let forged_query = format!("/ws?token={}", forged);
+        assert_eq!(
+            authenticate(vec![bearer, cookie], forged_query),
+            Ok(alice())
+        );
+        let forged_cookie = (header::COOKIE, format!("cast_me_token={}", forged));
+        let valid_query = format!("/ws?token={}", valid);
+        assert_eq!(
+            authenticate(vec![forged_cookie], valid_query),
+            Err(AuthError::Signature)
+        );
+
+        let wrong_names = (header::COOKIE, format!("token={}", valid));
+        let wrong_query = format!("/ws?cast_me_token={}", valid);
+        assert_eq!(
+            authenticate(vec![wrong_names], wrong_query),
+            Err(AuthError::Missing)
+        );
+        let basic = (
+            header::AUTHORIZATION,
+            String::from("Basic YWxpY2U6c2VjcmV0"),
+        );
+        assert_eq!(authenticate(vec![basic], plain), Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn truncated_der() {
+        let pem = include_bytes!("../testcerts/public.pem");
+        let spki = rustls_pemfile::public_keys(&mut pem.as_slice())
+            .next()
+            .unwrap()
+            .unwrap();
+        let spki = spki.as_ref();
+        assert!(rsa_public_key(spki).is_some());
+        for len in 0..spki.len() {
+            assert_eq!(rsa_public_key(&spki[..len]), None, "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn der_lengths() {
+        assert_eq!(
+            der(0x02, &[0x02, 0x01, 0x05, 0xff]),
+            Some((&[0x05][..], &[0xff][..]))
+        );
+        assert_eq!(
+            der(0x02, &[0x02, 0x81, 0x01, 0x05]),
+            Some((&[0x05][..], &[][..]))
+        );
+        assert_eq!(der(0x30, &[0x02, 0x01, 0x05]), None);
+        assert_eq!(der(0x02, &[]), None);
+        assert_eq!(der(0x02, &[0x02]), None);
+        // longer than the input
+        assert_eq!(der(0x02, &[0x02, 0x02, 0x05]), None);
+        assert_eq!(der(0x02, &[0x02, 0x84, 0xff, 0xff, 0xff, 0xff, 0x05]), None);
+        // indefinite, more length octets than fit, fewer than announced
+        assert_eq!(der(0x02, &[0x02, 0x80, 0x05, 0x00, 0x00]), None);
+        assert_eq!(der(0x02, &[0x02, 0x85, 0, 0, 0, 0, 0x01, 0x05]), None);
+        assert_eq!(der(0x02, &[0x02, 0x82, 0x01]), None);
+    }
+}
@@ -1,11 +1,19 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use tokio::{
-    sync::mpsc,
+    sync::{mpsc, oneshot},
     task::{self, JoinHandle},
+    time,
 };
 
-use crate::PeerId;
+use crate::{
+    broker::{
+        BrokerStats, Inventory, PairingCodes, PeerInfo, PeerState, PeerSummary, PendingRequests,
+        SignalingBroker,
+    },
+    metrics::METRICS,
+    ConnectError, ConnectTo, IdGenerator, PairingCode, PeerId, RoomId, WsProtocol,
+};
 
 use super::{
     peer::{PeerMessage, PeerSender},
@@ -15,27 +23,99 @@ use super::{
 // Peer -> Broker Messages
 #[derive(Debug)]
 pub enum BrokerMsg {
-    Register { uuid: PeerId, peer: PeerSender },
-    Connect { from: PeerId, to: PeerId },
+    /// a new peer, answered with its id, `None` if there is no free one
+    Admit {
+        peer: PeerSender,
+        info: PeerInfo,
+        reply: oneshot::Sender<Option<PeerId>>,
+    },
+    Register {
+        uuid: PeerId,
+        peer: PeerSender,
+        info: PeerInfo,
+    },
+    /// a code for `uuid`, `None` if there is no free one
+    PairingCode {
+        uuid: PeerId,
+        reply: oneshot::Sender<Option<(PairingCode, Duration)>>,
+    },
+    Connect {
+        from: PeerId,
+        to: ConnectTo,
+        reply: oneshot::Sender<Result<(), ConnectError>>,
+    },
+    /// `uuid` answers the connect request of `from`
+    Respond {
+        uuid: PeerId,
+        from: PeerId,
+        accept: bool,
+        reply: oneshot::Sender<Result<(), ConnectError>>,
+    },
+    /// connect requests that were not answered in time fail, pairing codes run out
+    ExpireRequests,
+    Join {
+        room: RoomId,
+        uuid: PeerId,
+        peer: PeerSender,
+    },
+    Leave {
+        room: RoomId,
+        uuid: PeerId,
+    },
+    ToRoom {
+        room: RoomId,
+        from: PeerId,
+        to: Option<PeerId>,
+        message: PeerMessage,
+    },
+    Shutdown {
+        reconnect_after: Duration,
+    },
+    HealthCheck {
+        reply: oneshot::Sender<()>,
+    },
+    Stats {
+        reply: oneshot::Sender<BrokerStats>,
+    },
+    Inventory {
+        reply: oneshot::Sender<Inventory>,
+    },
+    Kick {
+        uuid: PeerId,
+        reply: oneshot::Sender<bool>,
+    },
+    Broadcast {
+        message: String,
+        reply: oneshot::Sender<usize>,
+    },
 }
 
-#[derive(Clone)]
+type Rooms = HashMap<RoomId, HashMap<PeerId, PeerSender>>;
+
+/// how often unanswered connect requests and used up pairing codes are looked for
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
 pub struct Broker {
     to_broker: Sender<BrokerMsg>,
 }
 
 impl Broker {
-    pub fn addr(self) -> Sender<BrokerMsg> {
+    pub fn send(&self, msg: BrokerMsg) -> anyhow::Result<()> {
         self.to_broker
+            .send(msg)
+            .map_err(|_| anyhow::anyhow!("broker loop has ended"))
     }
 
     fn register_peer(
         loose_channels: &mut HashMap<PeerId, PeerSender>,
+        paired: &mut HashMap<PeerId, PeerSender>,
         uuid: &PeerId,
         peer: PeerSender,
     ) {
+        paired.remove(uuid);
         if let Some(_peer) = loose_channels.insert(uuid.clone(), peer) {
-            tracing::warn!("uuid collision {}", uuid);
+            tracing::warn!("{} registered again while it was in the pool", uuid);
         }
         tracing::info!(
             "registered peer under {} {:#?}",
@@ -44,67 +124,607 @@ impl Broker {
         );
     }
 
-    fn connect_peers(loose_channels: &mut HashMap<PeerId, PeerSender>, from: &PeerId, to: &PeerId) {
-        // don't be fooled
+    /// Whether `from` may ask `to`, or be paired with it.
+    fn check_connectable(
+        loose_channels: &mut HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+        from: &PeerId,
+        to: &PeerId,
+    ) -> Result<(), ConnectError> {
         if from == to {
-            if let Some(bad_guy) = loose_channels.remove(from) {
-                if let Err(e) = bad_guy.send(PeerMessage::Close) {
-                    tracing::warn!("failed to send close to {} {}", from, e);
-                }
-                return;
+            return Err(ConnectError::SelfConnect);
+        }
+
+        if !loose_channels.contains_key(from) {
+            return Err(ConnectError::NotRegistered);
+        }
+
+        if paired.contains_key(to) || rooms.values().any(|members| members.contains_key(to)) {
+            return Err(ConnectError::AlreadyPaired);
+        }
+
+        match loose_channels.get(to) {
+            None => return Err(ConnectError::NotFound),
+            Some(peer_b) if peer_b.is_closed() => {
+                loose_channels.remove(to);
+                return Err(ConnectError::NotRunning);
             }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Asks `to` whether `from` may connect, see [`BrokerMsg::Respond`].
+    fn request_connection(
+        loose_channels: &mut HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+        infos: &HashMap<PeerId, PeerInfo>,
+        pending: &mut PendingRequests,
+        from: &PeerId,
+        to: &PeerId,
+    ) -> Result<(), ConnectError> {
+        Self::check_connectable(loose_channels, paired, rooms, from, to)?;
+
+        let request = WsProtocol::ConnectRequest {
+            from: from.clone(),
+            display_name: infos
+                .get(from)
+                .and_then(|info| info.user.as_ref())
+                .map(|user| user.display_name().to_owned()),
+        };
+        if let Err(e) = loose_channels[to].send(PeerMessage::P2P(request.to_string())) {
+            tracing::debug!("failed to ask {} for {}, {}", to, from, e);
+            loose_channels.remove(to);
+            return Err(ConnectError::NotRunning);
         }
+        tracing::info!("{} asks {} to connect", from, to);
+        pending.insert(from.clone(), to.clone());
+        Ok(())
+    }
 
-        if let (true, Some(peer_b)) = (loose_channels.contains_key(from), loose_channels.remove(to))
+    /// A protocol message for a peer in the pool, which may be gone by now.
+    fn tell(loose_channels: &HashMap<PeerId, PeerSender>, uuid: &PeerId, message: WsProtocol) {
+        let Some(peer) = loose_channels.get(uuid) else {
+            tracing::debug!("{} is no longer waiting for {}", uuid, message);
+            return;
+        };
+        if let Err(e) = peer.send(PeerMessage::P2P(message.to_string())) {
+            tracing::debug!("failed to tell {} {}, {}", uuid, message, e);
+        }
+    }
+
+    /// Tells both peers about each other, they only leave the pool if both could be told.
+    fn connect_peers(
+        loose_channels: &mut HashMap<PeerId, PeerSender>,
+        paired: &mut HashMap<PeerId, PeerSender>,
+        partners: &mut HashMap<PeerId, PeerId>,
+        rooms: &Rooms,
+        infos: &HashMap<PeerId, PeerInfo>,
+        from: &PeerId,
+        to: &PeerId,
+    ) -> Result<(), ConnectError> {
+        Self::check_connectable(loose_channels, paired, rooms, from, to)?;
+
+        let peer_a = loose_channels[from].clone();
+        let peer_b = loose_channels[to].clone();
+        // before anybody is told, so nobody is left believing it is connected
+        if peer_a.is_closed() {
+            tracing::warn!("failed to connect {} to {}, it has gone away", from, to);
+            loose_channels.remove(from);
+            return Err(ConnectError::DeliveryFailed);
+        }
+
+        let user = |uuid| infos.get(uuid).and_then(|info| info.user.clone());
+        tracing::info!("connecting peers {} and {}", from, to);
+        if let Err(err) = peer_a.send(PeerMessage::Connected(peer_b.clone(), to.clone(), user(to)))
         {
-            let peer_a = loose_channels.remove(from).unwrap();
-            tracing::info!("connecting peers {} and {}", from, to);
-            match (
-                peer_a.send(PeerMessage::Connected(peer_b.clone(), to.clone())),
-                peer_b.send(PeerMessage::Connected(peer_a, from.clone())),
-            ) {
-                (Err(err), _) => tracing::error!("failed to send b to a, reason: {}", err),
-                (_, Err(err)) => tracing::error!("failed to send a to b, reason: {}", err),
-                _ => tracing::info!(
-                    "connected {} with {} | inventory={:#?}",
-                    from,
-                    to,
-                    loose_channels.keys()
-                ),
+            tracing::error!("failed to send b to a, reason: {}", err);
+            loose_channels.remove(from);
+            return Err(ConnectError::DeliveryFailed);
+        }
+        if let Err(err) = peer_b.send(PeerMessage::Connected(
+            peer_a.clone(),
+            from.clone(),
+            user(from),
+        )) {
+            tracing::error!("failed to send a to b, reason: {}", err);
+            loose_channels.remove(to);
+            // a registers again once it hears b is gone
+            loose_channels.remove(from);
+            if let Err(e) = peer_a.send(PeerMessage::Disconnected) {
+                tracing::debug!("failed to tell {} that {} is gone, {}", from, to, e);
+            }
+            return Err(ConnectError::DeliveryFailed);
+        }
+
+        loose_channels.remove(from);
+        loose_channels.remove(to);
+        tracing::info!(
+            "connected {} with {} | inventory={:#?}",
+            from,
+            to,
+            loose_channels.keys()
+        );
+        paired.insert(from.clone(), peer_a);
+        paired.insert(to.clone(), peer_b);
+        partners.insert(from.clone(), to.clone());
+        partners.insert(to.clone(), from.clone());
+        Ok(())
+    }
+
+    /// returns how many members `message` reached
+    fn send_to_room(
+        rooms: &mut Rooms,
+        room: &RoomId,
+        from: &PeerId,
+        to: Option<&PeerId>,
+        message: &PeerMessage,
+    ) -> usize {
+        let Some(members) = rooms.get_mut(room) else {
+            tracing::warn!("room {} not found", room);
+            return 0;
+        };
+        let mut reached = 0;
+        members.retain(|uuid, peer| {
+            if uuid == from || to.is_some_and(|to| to != uuid) {
+                return true;
+            }
+            if let Err(e) = peer.send(message.clone()) {
+                tracing::debug!("removing member {} from {}, {}", uuid, room, e);
+                return false;
+            }
+            reached += 1;
+            true
+        });
+        reached
+    }
+
+    fn join_room(
+        loose_channels: &mut HashMap<PeerId, PeerSender>,
+        rooms: &mut Rooms,
+        room: &RoomId,
+        uuid: &PeerId,
+        peer: PeerSender,
+    ) {
+        // members of a room can't be connected to directly
+        loose_channels.remove(uuid);
+
+        let members = rooms.entry(room.clone()).or_default();
+        let joined = WsProtocol::Joined {
+            room: room.clone(),
+            members: members.keys().cloned().collect(),
+        };
+        if let Err(e) = peer.send(PeerMessage::P2P(joined.to_string())) {
+            tracing::warn!("failed to confirm join to {} {}", uuid, e);
+            return;
+        }
+        members.insert(uuid.clone(), peer);
+        tracing::info!("{} joined {} | members={:#?}", uuid, room, members.keys());
+
+        let announcement = WsProtocol::PeerJoined {
+            room: room.clone(),
+            peer: uuid.clone(),
+        };
+        Self::send_to_room(
+            rooms,
+            room,
+            uuid,
+            None,
+            &PeerMessage::P2P(announcement.to_string()),
+        );
+    }
+
+    fn leave_room(rooms: &mut Rooms, room: &RoomId, uuid: &PeerId) {
+        let Some(members) = rooms.get_mut(room) else {
+            tracing::warn!("room {} not found", room);
+            return;
+        };
+        if members.remove(uuid).is_none() {
+            tracing::warn!("{} is not a member of {}", uuid, room);
+            return;
+        }
+        if members.is_empty() {
+            rooms.remove(room);
+            return;
+        }
+        tracing::info!("{} left {}", uuid, room);
+
+        let announcement = WsProtocol::PeerLeft {
+            room: room.clone(),
+            peer: uuid.clone(),
+        };
+        Self::send_to_room(
+            rooms,
+            room,
+            uuid,
+            None,
+            &PeerMessage::P2P(announcement.to_string()),
+        );
+    }
+
+    /// Loose, paired and room members alike.
+    fn everyone<'a>(
+        loose_channels: &'a HashMap<PeerId, PeerSender>,
+        paired: &'a HashMap<PeerId, PeerSender>,
+        rooms: &'a Rooms,
+    ) -> impl Iterator<Item = (&'a PeerId, &'a PeerSender)> {
+        let members = rooms.values().flat_map(|members| members.iter());
+        loose_channels.iter().chain(paired).chain(members)
+    }
+
+    fn shut_down_peers(
+        loose_channels: &HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+        reconnect_after: Duration,
+    ) {
+        for (uuid, peer) in Self::everyone(loose_channels, paired, rooms) {
+            if let Err(e) = peer.send(PeerMessage::Shutdown(reconnect_after)) {
+                tracing::debug!("failed to shut down {}, {}", uuid, e);
             }
-        } else {
-            tracing::warn!("no uuid match {} {}", from, to);
         }
     }
 
+    fn take_inventory(
+        loose_channels: &HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+        partners: &HashMap<PeerId, PeerId>,
+        infos: &HashMap<PeerId, PeerInfo>,
+    ) -> Inventory {
+        let summary = |uuid: &PeerId, state| PeerSummary {
+            id: uuid.clone(),
+            state,
+            info: infos.get(uuid).cloned(),
+        };
+        let alive = |peers: &HashMap<PeerId, PeerSender>| {
+            peers
+                .iter()
+                .filter(|(_, peer)| !peer.is_closed())
+                .map(|(uuid, _)| uuid.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut inventory = Inventory::default();
+        for uuid in alive(loose_channels) {
+            inventory.peers.push(summary(&uuid, PeerState::Unpaired));
+        }
+        let paired = alive(paired);
+        for uuid in &paired {
+            inventory.peers.push(summary(uuid, PeerState::Paired));
+        }
+        for (room, members) in rooms {
+            let members = alive(members);
+            for uuid in &members {
+                inventory.peers.push(summary(uuid, PeerState::InRoom));
+            }
+            inventory.rooms.insert(room.clone(), members);
+        }
+
+        // the partners of peers that are gone stay around until the next clean out
+        let partners = partners
+            .iter()
+            .filter(|(uuid, partner)| paired.contains(uuid) && paired.contains(partner))
+            .map(|(uuid, partner)| (uuid.clone(), partner.clone()))
+            .collect();
+        inventory.pairs = Inventory::pairs_of(&partners);
+        inventory
+    }
+
+    fn kick_peer(
+        loose_channels: &HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+        uuid: &PeerId,
+    ) -> bool {
+        let Some((_, peer)) =
+            Self::everyone(loose_channels, paired, rooms).find(|(id, _)| *id == uuid)
+        else {
+            return false;
+        };
+        tracing::info!("kicking {}", uuid);
+        if let Err(e) = peer.send(PeerMessage::Close) {
+            tracing::debug!("failed to kick {}, {}", uuid, e);
+            return false;
+        }
+        true
+    }
+
+    fn broadcast_notice(
+        loose_channels: &HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+        message: String,
+    ) -> usize {
+        let notice = WsProtocol::Notice { message }.to_string();
+        Self::everyone(loose_channels, paired, rooms)
+            .filter(|(_, peer)| peer.send(PeerMessage::P2P(notice.clone())).is_ok())
+            .count()
+    }
+
+    /// Forget where peers came from and whom they were paired with once they are gone.
+    fn forget_gone_peers(
+        infos: &mut HashMap<PeerId, PeerInfo>,
+        partners: &mut HashMap<PeerId, PeerId>,
+        loose_channels: &HashMap<PeerId, PeerSender>,
+        paired: &HashMap<PeerId, PeerSender>,
+        rooms: &Rooms,
+    ) {
+        let known = |uuid: &PeerId| {
+            loose_channels.contains_key(uuid)
+                || paired.contains_key(uuid)
+                || rooms.values().any(|members| members.contains_key(uuid))
+        };
+        infos.retain(|uuid, _| known(uuid));
+        partners.retain(|uuid, _| paired.contains_key(uuid));
+    }
+
     fn clean_out_dead_peers(loose_channels: &mut HashMap<PeerId, PeerSender>) {
         loose_channels.retain(|uuid, peer| {
             if let Err(e) = peer.send(PeerMessage::Ping) {
                 tracing::debug!("removing peer {}, {}", uuid, e);
+                METRICS.gc_removed.inc();
                 return false;
             };
             true
         });
     }
 
-    pub fn create() -> (Broker, JoinHandle<()>) {
+    /// New peers get their ids from `ids`, requests that are not answered within
+    /// `connect_timeout` fail, pairing codes can be used for `code_ttl`.
+    pub fn create(
+        ids: IdGenerator,
+        connect_timeout: Duration,
+        code_ttl: Duration,
+    ) -> (Broker, JoinHandle<()>) {
         let (tx, mut rx) = mpsc::unbounded_channel();
 
         // only those that don't have a partner yet
         let mut loose_channels: HashMap<PeerId, PeerSender> = HashMap::new();
+        // those that are connected to a correspondent
+        let mut paired: HashMap<PeerId, PeerSender> = HashMap::new();
+        let mut rooms: Rooms = HashMap::new();
+        // whom the paired peers are paired with
+        let mut partners: HashMap<PeerId, PeerId> = HashMap::new();
+        // where and when the peers connected, for the admin api
+        let mut infos: HashMap<PeerId, PeerInfo> = HashMap::new();
+        // connect requests waiting for an answer
+        let mut pending = PendingRequests::new(connect_timeout);
+        // pairing codes that stand for peers
+        let mut codes = PairingCodes::new(code_ttl);
+
+        // reminds the loop to expire requests, as long as anybody holds the broker
+        let reminder = tx.downgrade();
+        task::spawn(async move {
+            let mut ticks = time::interval(EXPIRE_INTERVAL);
+            loop {
+                ticks.tick().await;
+                let Some(tx) = reminder.upgrade() else {
+                    break;
+                };
+                if tx.send(BrokerMsg::ExpireRequests).is_err() {
+                    break;
+                }
+            }
+        });
 
         let broker_loop = task::spawn(async move {
             tracing::debug!("broker loop");
             while let Some(res) = rx.recv().await {
                 tracing::debug!("broker received {:?}", res);
                 match res {
-                    BrokerMsg::Register { uuid, peer } => {
+                    BrokerMsg::Admit { peer, info, reply } => {
+                        Self::clean_out_dead_peers(&mut loose_channels);
+                        Self::clean_out_dead_peers(&mut paired);
+                        // dead room members keep their ids until they are cleaned out
+                        let uuid = ids.generate_unique(|uuid| {
+                            Self::everyone(&loose_channels, &paired, &rooms)
+                                .any(|(other, _)| other == uuid)
+                        });
+                        if let Some(uuid) = &uuid {
+                            Self::register_peer(&mut loose_channels, &mut paired, uuid, peer);
+                            infos.insert(uuid.clone(), info);
+                        } else {
+                            tracing::error!("no free peer id left, refusing new peer");
+                        }
+                        if reply.send(uuid).is_err() {
+                            tracing::debug!("new peer is gone before it was admitted");
+                        }
+                    }
+
+                    BrokerMsg::Register { uuid, peer, info } => {
                         Self::clean_out_dead_peers(&mut loose_channels);
-                        Self::register_peer(&mut loose_channels, &uuid, peer);
+                        Self::clean_out_dead_peers(&mut paired);
+                        partners.remove(&uuid);
+                        Self::register_peer(&mut loose_channels, &mut paired, &uuid, peer);
+                        infos.insert(uuid, info);
+                        Self::forget_gone_peers(
+                            &mut infos,
+                            &mut partners,
+                            &loose_channels,
+                            &paired,
+                            &rooms,
+                        );
+                    }
+
+                    BrokerMsg::PairingCode { uuid, reply } => {
+                        let issued = codes.issue(&uuid);
+                        if issued.is_none() {
+                            tracing::error!("no free pairing code left for {}", uuid);
+                        }
+                        if reply.send(issued).is_err() {
+                            tracing::debug!("{} is no longer waiting for a code", uuid);
+                        }
+                    }
+
+                    BrokerMsg::Connect { from, to, reply } => {
+                        let result = codes.resolve(&to).and_then(|uuid| {
+                            Self::request_connection(
+                                &mut loose_channels,
+                                &paired,
+                                &rooms,
+                                &infos,
+                                &mut pending,
+                                &from,
+                                &uuid,
+                            )
+                        });
+                        match &result {
+                            Ok(()) => codes.use_up(&to),
+                            Err(error) => {
+                                tracing::warn!("failed to connect {} to {}: {}", from, to, error)
+                            }
+                        }
+                        if reply.send(result).is_err() {
+                            tracing::debug!("{} is no longer waiting for {}", from, to);
+                        }
+                    }
+
+                    BrokerMsg::Respond {
+                        uuid,
+                        from,
+                        accept,
+                        reply,
+                    } => {
+                        let result = if !pending.take(&from, &uuid) {
+                            Err(ConnectError::NoRequest)
+                        } else if !accept {
+                            tracing::info!("{} rejected {}", uuid, from);
+                            METRICS
+                                .connects
+                                .record(&Err::<(), _>(ConnectError::Rejected));
+                            let rejected = WsProtocol::from(ConnectError::Rejected);
+                            Self::tell(&loose_channels, &from, rejected);
+                            Ok(())
+                        } else {
+                            let connected = Self::connect_peers(
+                                &mut loose_channels,
+                                &mut paired,
+                                &mut partners,
+                                &rooms,
+                                &infos,
+                                &uuid,
+                                &from,
+                            );
+                            METRICS.connects.record(&connected);
+                            match connected {
+                                Ok(()) => {
+                                    let mut requesters = pending.take_involving(&uuid);
+                                    requesters.extend(pending.take_involving(&from));
+                                    for requester in requesters {
+                                        let busy = WsProtocol::from(ConnectError::AlreadyPaired);
+                                        Self::tell(&loose_channels, &requester, busy);
+                                    }
+                                }
+                                Err(error) => {
+                                    tracing::warn!(
+                                        "failed to connect {} to {}: {}",
+                                        from,
+                                        uuid,
+                                        error
+                                    );
+                                    Self::tell(&loose_channels, &from, WsProtocol::from(error));
+                                }
+                            }
+                            connected
+                        };
+                        if reply.send(result).is_err() {
+                            tracing::debug!("{} is no longer waiting for its answer", uuid);
+                        }
+                    }
+
+                    BrokerMsg::ExpireRequests => {
+                        codes.expire();
+                        for (from, to) in pending.take_expired() {
+                            tracing::info!("{} did not answer {} in time", to, from);
+                            METRICS
+                                .connects
+                                .record(&Err::<(), _>(ConnectError::TimedOut));
+                            let timed_out = WsProtocol::from(ConnectError::TimedOut);
+                            Self::tell(&loose_channels, &from, timed_out);
+                        }
+                    }
+
+                    BrokerMsg::Join { room, uuid, peer } => {
+                        Self::join_room(&mut loose_channels, &mut rooms, &room, &uuid, peer);
                     }
 
-                    BrokerMsg::Connect { from, to } => {
-                        Self::connect_peers(&mut loose_channels, &from, &to);
+                    BrokerMsg::Leave { room, uuid } => {
+                        Self::leave_room(&mut rooms, &room, &uuid);
+                    }
+
+                    BrokerMsg::ToRoom {
+                        room,
+                        from,
+                        to,
+                        message,
+                    } => {
+                        if rooms
+                            .get(&room)
+                            .is_some_and(|members| members.contains_key(&from))
+                        {
+                            let reached =
+                                Self::send_to_room(&mut rooms, &room, &from, to.as_ref(), &message);
+                            for _ in 0..reached {
+                                message.count_forwarded();
+                            }
+                        } else {
+                            tracing::warn!("{} is not a member of {}", from, room);
+                        }
+                    }
+
+                    BrokerMsg::Shutdown { reconnect_after } => {
+                        Self::shut_down_peers(&loose_channels, &paired, &rooms, reconnect_after);
+                    }
+
+                    BrokerMsg::Stats { reply } => {
+                        let alive = |peers: &HashMap<PeerId, PeerSender>| {
+                            peers.values().filter(|peer| !peer.is_closed()).count()
+                        };
+                        let stats = BrokerStats {
+                            unpaired: alive(&loose_channels),
+                            pairs: alive(&paired) / 2,
+                            room_members: rooms.values().map(alive).sum(),
+                        };
+                        if reply.send(stats).is_err() {
+                            tracing::debug!("nobody is waiting for the stats");
+                        }
+                    }
+
+                    BrokerMsg::HealthCheck { reply } => {
+                        if reply.send(()).is_err() {
+                            tracing::debug!("nobody is waiting for the health check");
+                        }
+                    }
+
+                    BrokerMsg::Inventory { reply } => {
+                        let inventory = Self::take_inventory(
+                            &loose_channels,
+                            &paired,
+                            &rooms,
+                            &partners,
+                            &infos,
+                        );
+                        if reply.send(inventory).is_err() {
+                            tracing::debug!("nobody is waiting for the inventory");
+                        }
+                    }
+
+                    BrokerMsg::Kick { uuid, reply } => {
+                        let kicked = Self::kick_peer(&loose_channels, &paired, &rooms, &uuid);
+                        if reply.send(kicked).is_err() {
+                            tracing::debug!("nobody is waiting for the kick of {}", uuid);
+                        }
+                    }
+
+                    BrokerMsg::Broadcast { message, reply } => {
+                        let reached =
+                            Self::broadcast_notice(&loose_channels, &paired, &rooms, message);
+                        if reply.send(reached).is_err() {
+                            tracing::debug!("nobody is waiting for the broadcast");
+                        }
                     }
                 }
             }
@@ -113,3 +733,174 @@ impl Broker {
         (Broker { to_broker: tx }, broker_loop)
     }
 }
+
+impl SignalingBroker for Broker {
+    type Peer = PeerSender;
+
+    async fn admit(&self, peer: PeerSender, info: PeerInfo) -> anyhow::Result<PeerId> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::Admit { peer, info, reply })?;
+        response
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("no free peer id left"))
+    }
+
+    async fn register(&self, id: PeerId, peer: PeerSender, info: PeerInfo) -> anyhow::Result<()> {
+        self.send(BrokerMsg::Register {
+            uuid: id,
+            peer,
+            info,
+        })
+    }
+
+    async fn pairing_code(&self, id: PeerId) -> anyhow::Result<(PairingCode, Duration)> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::PairingCode { uuid: id, reply })?;
+        response
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("no free pairing code left"))
+    }
+
+    async fn connect(&self, from: PeerId, to: ConnectTo) -> Result<(), ConnectError> {
+        let (reply, response) = oneshot::channel();
+        let result = match self.send(BrokerMsg::Connect { from, to, reply }) {
+            Ok(()) => response.await.unwrap_or(Err(ConnectError::DeliveryFailed)),
+            Err(_) => Err(ConnectError::DeliveryFailed),
+        };
+        // the outcome of a request is counted once it is answered
+        if result.is_err() {
+            METRICS.connects.record(&result);
+        }
+        result
+    }
+
+    async fn respond(&self, id: PeerId, from: PeerId, accept: bool) -> Result<(), ConnectError> {
+        let (reply, response) = oneshot::channel();
+        let respond = BrokerMsg::Respond {
+            uuid: id,
+            from,
+            accept,
+            reply,
+        };
+        match self.send(respond) {
+            Ok(()) => response.await.unwrap_or(Err(ConnectError::DeliveryFailed)),
+            Err(_) => Err(ConnectError::DeliveryFailed),
+        }
+    }
+
+    async fn join(&self, room: RoomId, id: PeerId, peer: PeerSender) -> anyhow::Result<()> {
+        self.send(BrokerMsg::Join {
+            room,
+            uuid: id,
+            peer,
+        })
+    }
+
+    async fn leave(&self, room: RoomId, id: PeerId) -> anyhow::Result<()> {
+        self.send(BrokerMsg::Leave { room, uuid: id })
+    }
+
+    async fn stats(&self) -> anyhow::Result<BrokerStats> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::Stats { reply })?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("broker loop has ended"))
+    }
+
+    async fn health_check(&self) -> anyhow::Result<()> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::HealthCheck { reply })?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("broker loop has ended"))
+    }
+
+    async fn shutdown(&self, reconnect_after: Duration) -> anyhow::Result<()> {
+        self.send(BrokerMsg::Shutdown { reconnect_after })
+    }
+
+    async fn inventory(&self) -> anyhow::Result<Inventory> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::Inventory { reply })?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("broker loop has ended"))
+    }
+
+    async fn kick(&self, id: PeerId) -> anyhow::Result<bool> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::Kick { uuid: id, reply })?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("broker loop has ended"))
+    }
+
+    async fn broadcast(&self, message: String) -> anyhow::Result<usize> {
+        let (reply, response) = oneshot::channel();
+        self.send(BrokerMsg::Broadcast { message, reply })?;
+        response
+            .await
+            .map_err(|_| anyhow::anyhow!("broker loop has ended"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{channel::mpsc as client, SinkExt as _};
+
+    use std::future::Future;
+
+    use crate::{
+        basic::Peer,
+        broker::conformance::{self, conformance_tests, Conformance, TestPeer},
+        keepalive::Timeouts,
+        rate_limit::RateLimiter,
+        IceServers, RateLimitConfig, SessionConfig,
+    };
+
+    use super::*;
+
+    impl Conformance for Broker {
+        async fn spawn_peer(&self) -> TestPeer<PeerSender> {
+            let (sender, sent) = client::unbounded();
+            let (client, received) = client::unbounded();
+            let timeouts = Timeouts::from(&SessionConfig {
+                ping_interval_secs: 0,
+                ..Default::default()
+            });
+            let mut peer = Peer::new(
+                sender.sink_map_err(axum::Error::new),
+                received,
+                self.clone(),
+                timeouts,
+                PeerInfo::new(([127, 0, 0, 1], 4711).into(), None),
+                RateLimiter::new(&RateLimitConfig::default()),
+            );
+            peer.admit().await.unwrap();
+            peer.send_welcome(IceServers::default()).await;
+            let peer_sender = peer.peer_sender.clone();
+            let running = task::spawn(async move { peer.start().await });
+            TestPeer::welcomed(peer_sender, client, sent, running).await
+        }
+
+        async fn dead_peer(&self) -> PeerSender {
+            let (peer, _) = mpsc::unbounded_channel();
+            peer
+        }
+    }
+
+    fn run<F: Future>(case: impl FnOnce(Broker) -> F) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (broker, _) = Broker::create(
+                IdGenerator::default(),
+                conformance::CONNECT_TIMEOUT,
+                Duration::from_secs(120),
+            );
+            case(broker).await;
+        });
+    }
+
+    conformance_tests!(run);
+}
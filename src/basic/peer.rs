@@ -1,33 +1,68 @@
 #![allow(clippy::suspicious_else_formatting)]
 
-use futures::{
-    sink::SinkExt,
-    stream::{SplitSink, SplitStream},
-    StreamExt,
+use axum::{
+    body::Bytes,
+    extract::ws::{close_code, CloseFrame, Message},
+};
+use futures::{sink::SinkExt, Sink, Stream, StreamExt};
+use tokio::{sync::mpsc, time};
+
+use std::{fmt, pin::Pin, time::Duration};
+
+use crate::{
+    auth::Identity,
+    broker::{PeerInfo, SignalingBroker},
+    keepalive::{Check, Keepalive, Timeouts},
+    metrics::METRICS,
+    rate_limit::RateLimiter,
+    ws_protocol::{
+        unsupported_version, Capability, ErrorCode, PROTOCOL_VERSION, UNANNOUNCED_VERSION,
+    },
+    ConnectError, IceServers, PeerId, RoomId, WsProtocol,
 };
-use tokio::sync::mpsc;
-use warp::ws::{Message, WebSocket};
 
-use crate::{PeerId, WsProtocol};
+use super::{Broker, BrokerMsg, Receiver, Sender};
 
-use super::{BrokerMsg, Receiver, Sender};
+type WsSender = Pin<Box<dyn Sink<Message, Error = axum::Error> + Send>>;
+type WsReceiver = Pin<Box<dyn Stream<Item = Result<Message, axum::Error>> + Send>>;
 
-type WsSender = SplitSink<WebSocket, Message>;
-type WsReceiver = SplitStream<WebSocket>;
+/// everything this backend supports, sessions can't be resumed
+const CAPABILITIES: &[Capability] = &[
+    Capability::Rooms,
+    Capability::Signaling,
+    Capability::Binary,
+    Capability::PairingCodes,
+];
 
 pub type PeerSender = Sender<PeerMessage>;
 pub type PeerReceiver = Receiver<PeerMessage>;
 
 // P2P: Broker -> Peer Messages
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum PeerMessage {
     P2P(String),
-    Connected(PeerSender, PeerId),
+    P2PBinary(Bytes),
+    /// the correspondent, along with the user it authenticated as
+    Connected(PeerSender, PeerId, Option<Identity>),
     Disconnected,
     Ping,
+    /// the server is going away
+    Shutdown(Duration),
+    /// kicked by an operator
     Close,
 }
 
+impl PeerMessage {
+    /// on its way from one client to another
+    pub fn count_forwarded(&self) {
+        match self {
+            PeerMessage::P2P(text) => METRICS.forwarded.text(text.len()),
+            PeerMessage::P2PBinary(data) => METRICS.forwarded.binary(data.len()),
+            _ => {}
+        }
+    }
+}
+
 impl From<&str> for PeerMessage {
     fn from(s: &str) -> Self {
         Self::P2P(s.into())
@@ -36,21 +71,30 @@ impl From<&str> for PeerMessage {
 
 mod protocol {}
 
+/// hands a frame of the client to its correspondent
+fn forward(
+    correspondent: &PeerSender,
+    message: PeerMessage,
+) -> Result<(), mpsc::error::SendError<PeerMessage>> {
+    message.count_forwarded();
+    correspondent.send(message)
+}
+
 #[derive(Debug)]
 pub enum SendError {
     NoCorrespondant,
     FailedToSendOnWebsocket(#[allow(dead_code)] mpsc::error::SendError<PeerMessage>),
 }
 
-#[derive(Debug)]
 pub struct Peer {
     pub id: PeerId,
 
     /// sender to other participating peer
     pub correspondent: Option<PeerSender>,
+    pub correspondent_id: Option<PeerId>,
 
-    /// sender to broker
-    pub broker_addr: Sender<BrokerMsg>,
+    /// handle to broker
+    pub broker: Broker,
 
     /// sender to this peer
     pub peer_sender: PeerSender,
@@ -63,72 +107,155 @@ pub struct Peer {
 
     /// receiver on websocket
     pub ws_receiver: WsReceiver,
+
+    /// room this peer is a member of
+    pub room: Option<RoomId>,
     retire: bool,
+
+    /// negotiated in the client's hello, which has to come first
+    capabilities: Option<Vec<Capability>>,
+
+    /// pings the websocket and notices when it goes quiet
+    keepalive: Keepalive,
+
+    /// where and when the websocket was opened
+    info: PeerInfo,
+
+    /// throttles connect requests
+    limiter: RateLimiter,
+}
+
+impl fmt::Debug for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Peer")
+            .field("id", &self.id)
+            .field("correspondent_id", &self.correspondent_id)
+            .field("room", &self.room)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Peer {
-    pub fn new(ws: WebSocket, broker_addr: Sender<BrokerMsg>) -> Self {
-        let my_id = PeerId::default();
+    pub fn new(
+        ws_sender: impl Sink<Message, Error = axum::Error> + Send + 'static,
+        ws_receiver: impl Stream<Item = Result<Message, axum::Error>> + Send + 'static,
+        broker: Broker,
+        timeouts: Timeouts,
+        info: PeerInfo,
+        limiter: RateLimiter,
+    ) -> Self {
+        let my_id = PeerId::unassigned();
         let (peer_sender, peer_receiver) = mpsc::unbounded_channel::<PeerMessage>();
 
-        let (ws_sender, ws_receiver) = ws.split();
-
         Peer {
             id: my_id,
             retire: false,
             correspondent: None,
-            broker_addr,
+            correspondent_id: None,
+            broker,
             peer_receiver,
             peer_sender,
-            ws_receiver,
-            ws_sender,
+            ws_receiver: Box::pin(ws_receiver),
+            ws_sender: Box::pin(ws_sender),
+            room: None,
+            capabilities: None,
+            keepalive: Keepalive::new(timeouts),
+            info,
+            limiter,
         }
     }
 
-    pub fn register_at_broker(&mut self) {
-        self.send_to_broker(BrokerMsg::Register {
-            uuid: self.id.clone(),
-            peer: self.peer_sender.clone(),
-        });
+    /// Takes the id the broker hands out, before the welcome is sent.
+    pub async fn admit(&mut self) -> anyhow::Result<()> {
+        self.id = self
+            .broker
+            .admit(self.peer_sender.clone(), self.info.clone())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn register_at_broker(&mut self) {
+        if let Err(e) = self
+            .broker
+            .register(self.id.clone(), self.peer_sender.clone(), self.info.clone())
+            .await
+        {
+            tracing::error!("failed to register {} {}", self.id, e);
+        }
     }
 
     #[tracing::instrument]
     pub async fn start(&mut self) {
+        let mut ticks = self
+            .keepalive
+            .period()
+            .map(|period| time::interval_at(time::Instant::now() + period, period));
         loop {
             tokio::select! {
                 Some(received) = self.ws_receiver.next() => {
                     tracing::trace!("received on ws {:?}", received);
                     if let Ok(ws_message) = received {
-
-                        if ws_message.is_close() { // TODO: I wish I could just match on the message itself
-                            self.retire = true;
-                            tracing::debug!("{:?} websocket disconnected", self.id);
-                            if let Err(error) = self.send_to_correspondent(PeerMessage::Disconnected).await {
-                                tracing::debug!("{:?}", error);
-                            }
-                            break
+                        if matches!(ws_message, Message::Text(_) | Message::Binary(_)) {
+                            self.keepalive.active();
+                        } else {
+                            self.keepalive.heard();
                         }
-
-                        match (&mut self.correspondent, ws_message.to_str()) {
-                            (None, Ok(_)) => {
-                                if let Ok(WsProtocol::Connect(uuid)) = ws_message.to_str().and_then(|s|serde_json::from_str(s).map_err(|_|())) {
-                                    tracing::debug!("connecting to {}", uuid);
-                                    self.send_to_broker(BrokerMsg::Connect {
-                                            from: self.id.clone(),
-                                            to: uuid,
-                                        });
-                                } else {
-                                    tracing::trace!("no corresponded, ignoring");
+                        let binary = self.speaks(Capability::Binary);
+                        match (ws_message, &mut self.correspondent) {
+                            (Message::Close(_), _) => {
+                                self.retire = true;
+                                tracing::debug!("{:?} websocket disconnected", self.id);
+                                if let Err(error) = self.send_to_correspondent(PeerMessage::Disconnected).await {
+                                    tracing::debug!("{:?}", error);
                                 }
+                                break
                             }
-                            (Some(ref mut correspondent), Ok(content)) => {
-                                if let Err(e) = correspondent.send(PeerMessage::P2P(content.into())) { // TODO: redundant repacking
-                                    tracing::debug!("failed to forward {}", e);
+                            (ws_message @ (Message::Text(_) | Message::Binary(_)), _) if self.capabilities.is_none() => {
+                                self.greet(ws_message).await;
+                                if self.retire {
                                     break;
                                 }
                             }
+                            (Message::Binary(_), _) if !binary => {
+                                self.send_to_remote(&WsProtocol::from(ErrorCode::NotNegotiated).to_string()).await;
+                            }
+                            (Message::Text(content), None) => {
+                                self.handle_ws_text(content.as_str()).await;
+                                if self.retire {
+                                    break;
+                                }
+                            }
+                            (Message::Text(content), Some(ref mut correspondent)) => {
+                                match serde_json::from_str::<WsProtocol>(content.as_str()) {
+                                    Ok(message) if message.is_signal() => {
+                                        if self.negotiated(&message).await {
+                                            self.route_signal(message);
+                                        }
+                                    }
+                                    _ => if let Err(e) = forward(correspondent, PeerMessage::P2P(content.to_string())) { // TODO: redundant repacking
+                                        tracing::debug!("failed to forward {}", e);
+                                        break;
+                                    }
+                                }
+                            }
+                            (Message::Binary(data), Some(ref mut correspondent)) => {
+                                if let Err(e) = forward(correspondent, PeerMessage::P2PBinary(data)) {
+                                    tracing::debug!("failed to forward binary {}", e);
+                                    break;
+                                }
+                            }
+                            (Message::Binary(data), None) => {
+                                if let Some(room) = self.room.clone() {
+                                    self.send_to_broker(BrokerMsg::ToRoom {
+                                        room,
+                                        from: self.id.clone(),
+                                        to: None,
+                                        message: PeerMessage::P2PBinary(data),
+                                    });
+                                }
+                            }
+                            // pings are answered by axum
                             _ => {}
-
                         }
                     } else {
                         tracing::warn!("unhandled message: {:?}", received);
@@ -142,16 +269,296 @@ impl Peer {
                         break;
                     }
                 }
+                _ = async {
+                    match ticks.as_mut() {
+                        Some(ticks) => ticks.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    self.check_alive().await;
+                    if self.retire {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(room) = self.room.take() {
+            if let Err(e) = self.broker.leave(room, self.id.clone()).await {
+                tracing::warn!("{} failed to leave room {}", self.id, e);
             }
         }
 
         tracing::info!("peer quit {}", self.id);
     }
 
-    #[tracing::instrument]
-    pub async fn send_welcome(&mut self) {
-        self.send_to_remote(&WsProtocol::Welcome(self.id.clone()).to_string())
+    /// signaling is checked, stamped and routed to the addressed peer only
+    fn route_signal(&self, message: WsProtocol) {
+        let (to, signal) = match message.stamp_signal(&self.id) {
+            Ok(stamped) => stamped,
+            Err(e) => {
+                tracing::warn!("{} sent invalid signal: {}", self.id, e);
+                return;
+            }
+        };
+
+        match (&self.correspondent, &self.correspondent_id, &self.room) {
+            (Some(correspondent), Some(correspondent_id), _) if *correspondent_id == to => {
+                if let Err(e) = forward(correspondent, PeerMessage::P2P(signal.to_string())) {
+                    tracing::debug!("failed to forward signal {}", e);
+                }
+            }
+            (None, _, Some(room)) => self.send_to_broker(BrokerMsg::ToRoom {
+                room: room.clone(),
+                from: self.id.clone(),
+                to: Some(to),
+                message: PeerMessage::P2P(signal.to_string()),
+            }),
+            _ => tracing::warn!("{} can't route signal to {}", self.id, to),
+        }
+    }
+
+    /// the client has to say hello first, anything else comes from an older one
+    async fn greet(&mut self, ws_message: Message) {
+        let hello = match &ws_message {
+            Message::Text(content) => serde_json::from_str(content.as_str()).ok(),
+            _ => None,
+        };
+        match hello {
+            Some(WsProtocol::Hello {
+                version,
+                capabilities,
+            }) => self.hello(version, &capabilities).await,
+            _ => self.reject(unsupported_version(UNANNOUNCED_VERSION)).await,
+        }
+    }
+
+    async fn hello(&mut self, version: u32, offered: &[Capability]) {
+        match WsProtocol::answer_hello(version, offered, CAPABILITIES) {
+            Ok((hello, capabilities)) => {
+                self.capabilities = Some(capabilities);
+                self.send_to_remote(&hello.to_string()).await;
+            }
+            Err(reason) => self.reject(reason).await,
+        }
+    }
+
+    fn speaks(&self, capability: Capability) -> bool {
+        self.capabilities
+            .as_ref()
+            .is_some_and(|negotiated| negotiated.contains(&capability))
+    }
+
+    /// whether the client negotiated what `message` needs, it is told if not
+    async fn negotiated(&mut self, message: &WsProtocol) -> bool {
+        match message.requires() {
+            Some(capability) if !self.speaks(capability) => {
+                tracing::debug!("{} did not negotiate {:?}", self.id, capability);
+                self.send_to_remote(&WsProtocol::from(ErrorCode::NotNegotiated).to_string())
+                    .await;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// text from the client while not paired to a correspondent
+    async fn handle_ws_text(&mut self, content: &str) {
+        let parsed = serde_json::from_str::<WsProtocol>(content);
+        if let Ok(message) = &parsed {
+            if !self.negotiated(message).await {
+                return;
+            }
+        }
+        match (parsed, self.room.clone()) {
+            (
+                Ok(WsProtocol::Hello {
+                    version,
+                    capabilities,
+                }),
+                _,
+            ) => self.hello(version, &capabilities).await,
+            (Ok(WsProtocol::Connect(uuid)), None) => {
+                tracing::debug!("connecting to {}", uuid);
+                let ip = self.info.remote_addr.ip();
+                if let Err(limited) = self.limiter.connect(ip, &self.id) {
+                    self.send_to_remote(&WsProtocol::from(limited).to_string())
+                        .await;
+                    return;
+                }
+                // once it accepts, the correspondent is handed over with `PeerMessage::Connected`
+                if let Err(error) = self.broker.connect(self.id.clone(), uuid).await {
+                    if error == ConnectError::NotFound {
+                        self.limiter.missed(ip);
+                    }
+                    self.send_to_remote(&WsProtocol::from(error).to_string())
+                        .await;
+                }
+            }
+            (Ok(WsProtocol::RequestCode), None) => {
+                let answer = match self.broker.pairing_code(self.id.clone()).await {
+                    Ok((code, ttl)) => WsProtocol::PairingCode {
+                        code,
+                        expires_in_secs: ttl.as_secs(),
+                    },
+                    Err(error) => {
+                        tracing::warn!("{} got no pairing code: {}", self.id, error);
+                        WsProtocol::from(ErrorCode::Internal)
+                    }
+                };
+                self.send_to_remote(&answer.to_string()).await;
+            }
+            (Ok(WsProtocol::Accept(uuid)), None) => self.respond(uuid, true).await,
+            (Ok(WsProtocol::Reject(uuid)), None) => self.respond(uuid, false).await,
+            (Ok(WsProtocol::Join(room)), None) => {
+                tracing::debug!("joining {}", room);
+                self.room.replace(room.clone());
+                if let Err(e) = self
+                    .broker
+                    .join(room, self.id.clone(), self.peer_sender.clone())
+                    .await
+                {
+                    tracing::warn!("{} failed to join room {}", self.id, e);
+                }
+            }
+            (Ok(WsProtocol::Leave), Some(room)) => {
+                tracing::debug!("leaving {}", room);
+                self.room = None;
+                if let Err(e) = self.broker.leave(room, self.id.clone()).await {
+                    tracing::warn!("{} failed to leave room {}", self.id, e);
+                }
+                self.register_at_broker().await;
+            }
+            (Ok(WsProtocol::SendTo { to, payload }), Some(room)) => {
+                let received = WsProtocol::ReceivedFrom {
+                    from: self.id.clone(),
+                    payload,
+                };
+                self.send_to_broker(BrokerMsg::ToRoom {
+                    room,
+                    from: self.id.clone(),
+                    to: Some(to),
+                    message: PeerMessage::P2P(received.to_string()),
+                });
+            }
+            (Err(_), Some(room)) => {
+                self.send_to_broker(BrokerMsg::ToRoom {
+                    room,
+                    from: self.id.clone(),
+                    to: None,
+                    message: PeerMessage::P2P(content.into()),
+                });
+            }
+            _ => tracing::trace!("no corresponded, ignoring"),
+        }
+    }
+
+    /// answer the connect request of `from`
+    async fn respond(&mut self, from: PeerId, accept: bool) {
+        tracing::debug!(
+            "{} {} {}",
+            self.id,
+            if accept { "accepts" } else { "rejects" },
+            from
+        );
+        if let Err(error) = self.broker.respond(self.id.clone(), from, accept).await {
+            self.send_to_remote(&WsProtocol::from(error).to_string())
+                .await;
+        }
+    }
+
+    async fn check_alive(&mut self) {
+        match self.keepalive.check() {
+            Check::Alive => {}
+            Check::Ping => {
+                if let Err(e) = self.ws_sender.send(Message::Ping(Bytes::new())).await {
+                    tracing::debug!("failed to ping {} {}", self.id, e);
+                }
+            }
+            Check::Unresponsive => self.time_out("ping timeout").await,
+            Check::Idle => {
+                self.send_to_remote(&WsProtocol::bye("idle").to_string())
+                    .await;
+                self.time_out("idle timeout").await;
+            }
+        }
+    }
+
+    /// close the websocket, the correspondent is told we're gone
+    async fn time_out(&mut self, reason: &'static str) {
+        tracing::info!("closing {}: {}", self.id, reason);
+        self.retire = true;
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: reason.into(),
+        }));
+        if let Err(e) = self.ws_sender.send(close).await {
+            tracing::debug!("failed to close websocket {}", e);
+        }
+        if let Err(error) = self.send_to_correspondent(PeerMessage::Disconnected).await {
+            tracing::debug!("{:?}", error);
+        }
+    }
+
+    /// tell the client when to come back and hang up
+    async fn shut_down(&mut self, reconnect_after: Duration) {
+        tracing::debug!("{} shutting down", self.id);
+        self.retire = true;
+        self.send_to_remote(&WsProtocol::shutting_down(reconnect_after).to_string())
             .await;
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: "server shutting down".into(),
+        }));
+        if let Err(e) = self.ws_sender.send(close).await {
+            tracing::debug!("failed to close websocket {}", e);
+        }
+    }
+
+    /// an operator wants us gone, the correspondent is told we left
+    async fn kick(&mut self) {
+        tracing::info!("{} kicked", self.id);
+        self.retire = true;
+        self.send_to_remote(&WsProtocol::bye("kicked").to_string())
+            .await;
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "kicked".into(),
+        }));
+        if let Err(e) = self.ws_sender.send(close).await {
+            tracing::debug!("failed to close websocket {}", e);
+        }
+        if let Err(error) = self.send_to_correspondent(PeerMessage::Disconnected).await {
+            tracing::debug!("{:?}", error);
+        }
+    }
+
+    /// say why and hang up
+    async fn reject(&mut self, reason: String) {
+        tracing::info!("rejecting {}: {}", self.id, reason);
+        self.retire = true;
+        self.send_to_remote(&WsProtocol::bye(reason).to_string())
+            .await;
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::PROTOCOL,
+            reason: "incompatible client".into(),
+        }));
+        if let Err(e) = self.ws_sender.send(close).await {
+            tracing::debug!("failed to close websocket {}", e);
+        }
+    }
+
+    #[tracing::instrument]
+    pub async fn send_welcome(&mut self, ice: IceServers) {
+        // sessions of the channel based broker can't be resumed
+        let welcome = WsProtocol::Welcome {
+            id: self.id.clone(),
+            token: None,
+            ice_servers: ice.for_peer(&self.id),
+            capabilities: CAPABILITIES.to_vec(),
+            version: PROTOCOL_VERSION,
+        };
+        self.send_to_remote(&welcome.to_string()).await;
     }
 
     #[tracing::instrument]
@@ -170,11 +577,19 @@ impl Peer {
     #[tracing::instrument]
     async fn send_to_remote(&mut self, msg: &str) {
         let payload = msg.to_string();
-        if let Err(e) = self.ws_sender.send(Message::text(&payload)).await {
+        if let Err(e) = self.ws_sender.send(Message::text(payload.as_str())).await {
             tracing::warn!("failed to send message on websocket {} {}", payload, e);
         }
     }
 
+    #[tracing::instrument(skip(payload))]
+    async fn send_binary_to_remote(&mut self, payload: Bytes) {
+        let len = payload.len();
+        if let Err(e) = self.ws_sender.send(Message::Binary(payload)).await {
+            tracing::warn!("failed to send {} bytes on websocket {}", len, e);
+        }
+    }
+
     #[tracing::instrument]
     async fn handle_broker_msg(
         // mut correspondent: &mut Option<PeerSender>,
@@ -189,42 +604,47 @@ impl Peer {
                 self.send_to_remote(content).await;
             }
 
+            (PeerMessage::P2PBinary(content), _) => {
+                tracing::trace!("peer received binary P2P");
+                self.send_binary_to_remote(content).await;
+            }
+
             (PeerMessage::Connected(..), Some(_)) => tracing::warn!("already have a correspondent"),
 
-            (PeerMessage::Connected(other_peer, other_peer_id), None) => {
+            (PeerMessage::Connected(other_peer, other_peer_id, user), None) => {
                 self.correspondent.replace(other_peer);
-                let hail = WsProtocol::Connected(other_peer_id);
+                self.correspondent_id.replace(other_peer_id.clone());
+                let hail = WsProtocol::Connected(other_peer_id.clone());
                 self.send_to_remote(&hail.to_string()).await;
+                if let Some(user) = user {
+                    let identified = WsProtocol::Identified {
+                        peer: other_peer_id,
+                        user,
+                    };
+                    self.send_to_remote(&identified.to_string()).await;
+                }
                 tracing::info!("set a correspondent");
             }
-            (PeerMessage::Close, _) => {
-                self.retire = true;
-                self.send_to_remote(
-                    &WsProtocol::Bye {
-                        reason: String::from("kicked"),
-                    }
-                    .to_string(),
-                )
-                .await;
-            }
             (PeerMessage::Disconnected, _) => {
-                tracing::debug!("{:?} peer left, retiring", self.id);
-                self.retire = true;
-                self.send_to_remote(
-                    &WsProtocol::Bye {
-                        reason: String::from("disconnected"),
-                    }
-                    .to_string(),
-                )
-                .await;
+                tracing::debug!("{:?} peer left", self.id);
+                self.correspondent = None;
+                self.correspondent_id = None;
+                // back to the pool before the client hears it can be connected to again
+                self.register_at_broker().await;
+                self.send_to_remote(&WsProtocol::bye("disconnected").to_string())
+                    .await;
             }
             (PeerMessage::Ping, _) => {
                 // I'm alive
             }
+            (PeerMessage::Shutdown(reconnect_after), _) => self.shut_down(reconnect_after).await,
+            (PeerMessage::Close, _) => self.kick().await,
         }
     }
 
     fn send_to_broker(&self, msg: BrokerMsg) {
-        self.broker_addr.send(msg).unwrap();
+        if let Err(e) = self.broker.send(msg) {
+            tracing::error!("{} lost the broker {}", self.id, e);
+        }
     }
 }
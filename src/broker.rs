@@ -0,0 +1,379 @@
+use serde::Serialize;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+#[cfg(test)]
+pub mod conformance;
+
+use crate::{auth::Identity, clock, ConnectError, ConnectTo, PairingCode, PeerId, RoomId};
+
+/// What a broker holds right now.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BrokerStats {
+    /// registered and waiting to be connected to
+    pub unpaired: usize,
+    /// pairs of connected peers
+    pub pairs: usize,
+    pub room_members: usize,
+}
+
+/// Where and when a peer's websocket was opened, and by whom.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerInfo {
+    pub remote_addr: SocketAddr,
+    /// unix timestamp
+    pub connected_at: u64,
+    /// `None` unless websockets require a token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<Identity>,
+}
+
+impl PeerInfo {
+    /// Connected just now.
+    pub fn new(remote_addr: SocketAddr, user: Option<Identity>) -> Self {
+        Self {
+            remote_addr,
+            connected_at: clock::unix_time(),
+            user,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PeerState {
+    /// registered and waiting to be connected to
+    Unpaired,
+    Paired,
+    InRoom,
+    /// the websocket dropped, the session can still be resumed
+    Suspended,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerSummary {
+    pub id: PeerId,
+    pub state: PeerState,
+    #[serde(flatten)]
+    pub info: Option<PeerInfo>,
+}
+
+/// Everyone a broker holds right now, for the admin api.
+#[derive(Debug, Default, Serialize)]
+pub struct Inventory {
+    pub peers: Vec<PeerSummary>,
+    pub pairs: Vec<[PeerId; 2]>,
+    pub rooms: HashMap<RoomId, Vec<PeerId>>,
+}
+
+impl Inventory {
+    /// Each pair once, `partners` maps both of them to each other.
+    pub fn pairs_of(partners: &HashMap<PeerId, PeerId>) -> Vec<[PeerId; 2]> {
+        let mut seen = HashSet::new();
+        partners
+            .iter()
+            .filter(|(id, partner)| partners.get(*partner) == Some(*id))
+            .filter(|(id, partner)| !seen.contains(*partner) && seen.insert(*id))
+            .map(|(id, partner)| [id.clone(), partner.clone()])
+            .collect()
+    }
+}
+
+/// Connect requests waiting for the peer they are addressed to, each peer has one at most.
+#[derive(Debug, Default)]
+pub struct PendingRequests {
+    /// by requesting peer, with whom it asked for and until when
+    requests: HashMap<PeerId, (PeerId, Instant)>,
+    timeout: Duration,
+}
+
+impl PendingRequests {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            requests: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// `from` asks for `to`, replacing the request it made before.
+    pub fn insert(&mut self, from: PeerId, to: PeerId) {
+        let expires = Instant::now() + self.timeout;
+        self.requests.insert(from, (to, expires));
+    }
+
+    /// Whether `from` asked for `to`, the request is answered either way.
+    pub fn take(&mut self, from: &PeerId, to: &PeerId) -> bool {
+        match self.requests.get(from) {
+            Some((requested, _)) if requested == to => {
+                self.requests.remove(from);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Requests that have not been answered in time, as `(from, to)`.
+    pub fn take_expired(&mut self) -> Vec<(PeerId, PeerId)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.requests.retain(|from, (to, expires)| {
+            if *expires > now {
+                return true;
+            }
+            expired.push((from.clone(), to.clone()));
+            false
+        });
+        expired
+    }
+
+    /// `id` was paired, it neither waits nor can be asked any longer.
+    ///
+    /// Returns those that asked for it.
+    pub fn take_involving(&mut self, id: &PeerId) -> Vec<PeerId> {
+        self.requests.remove(id);
+        let mut requesters = Vec::new();
+        self.requests.retain(|from, (to, _)| {
+            if to != id {
+                return true;
+            }
+            requesters.push(from.clone());
+            false
+        });
+        requesters
+    }
+}
+
+/// how often a code is drawn before giving up, most of them are handed out by then
+const CODE_ATTEMPTS: usize = 32;
+
+/// Pairing codes handed out to peers, each peer has one at most.
+#[derive(Debug, Default)]
+pub struct PairingCodes {
+    /// whose code it is and until when
+    codes: HashMap<PairingCode, (PeerId, Instant)>,
+    ttl: Duration,
+}
+
+impl PairingCodes {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            codes: HashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// A fresh code for `id`, replacing the one it had, `None` if no free code turns up.
+    pub fn issue(&mut self, id: &PeerId) -> Option<(PairingCode, Duration)> {
+        self.codes.retain(|_, (owner, _)| owner != id);
+        let code = std::iter::repeat_with(PairingCode::default)
+            .take(CODE_ATTEMPTS)
+            .find(|code| !self.codes.contains_key(code))?;
+        let expires = Instant::now() + self.ttl;
+        self.codes.insert(code.clone(), (id.clone(), expires));
+        Some((code, self.ttl))
+    }
+
+    /// Whom `to` stands for, see [`PairingCodes::use_up`].
+    ///
+    /// Codes that ran out or were used before are [`ConnectError::NotFound`].
+    pub fn resolve(&self, to: &ConnectTo) -> Result<PeerId, ConnectError> {
+        match to {
+            ConnectTo::Id(id) => Ok(id.clone()),
+            ConnectTo::Code(code) => match self.codes.get(code) {
+                Some((id, expires)) if *expires > Instant::now() => Ok(id.clone()),
+                _ => Err(ConnectError::NotFound),
+            },
+        }
+    }
+
+    /// A request made with `to` was delivered, a code can't be used again.
+    pub fn use_up(&mut self, to: &ConnectTo) {
+        if let ConnectTo::Code(code) = to {
+            self.codes.remove(code);
+        }
+    }
+
+    /// Forget codes that ran out.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.codes.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+/// The operations every broker implementation offers its peers.
+///
+/// [`crate::basic::Broker`] and [`crate::actors::Broker`] are held to the same contract by
+/// the cases in `broker::conformance`:
+///
+/// - a peer is either pooled, paired or in a room, only pooled peers can be connected to
+/// - nobody is paired before the asked peer accepts, the asking peer is told if it rejects
+///   or doesn't answer in time
+/// - if either of them can't be told they are paired, neither is and both are sent the error
+/// - peers that are gone are cleaned out lazily, they never show up as connectable
+/// - a peer whose correspondent leaves is told so and goes back into the pool
+pub trait SignalingBroker {
+    /// How the broker reaches a peer.
+    type Peer;
+
+    /// Adds a new peer to the pool under an id nobody else goes by, which is returned.
+    async fn admit(&self, peer: Self::Peer, info: PeerInfo) -> anyhow::Result<PeerId>;
+
+    /// Puts a peer that was admitted before back into the pool, e.g. after leaving a room.
+    async fn register(&self, id: PeerId, peer: Self::Peer, info: PeerInfo) -> anyhow::Result<()>;
+
+    /// A pairing code that stands for `id` in place of its previous one, and how long it can
+    /// be used. It is used up once a request made with it reached `id`.
+    async fn pairing_code(&self, id: PeerId) -> anyhow::Result<(PairingCode, Duration)>;
+
+    /// Asks `to` to accept `from`, the answer reaches both of them later on.
+    ///
+    /// Fails right away if `to` can't be asked, a later request of `from` replaces this one.
+    async fn connect(&self, from: PeerId, to: ConnectTo) -> Result<(), ConnectError>;
+
+    /// `id` accepts or rejects the request of `from`.
+    async fn respond(&self, id: PeerId, from: PeerId, accept: bool) -> Result<(), ConnectError>;
+
+    /// `id` leaves the pool for `room`, it is told who is in there and they are told about it.
+    async fn join(&self, room: RoomId, id: PeerId, peer: Self::Peer) -> anyhow::Result<()>;
+
+    /// The other members of `room` are told that `id` left.
+    async fn leave(&self, room: RoomId, id: PeerId) -> anyhow::Result<()>;
+
+    /// What the broker holds right now, peers that are gone aren't counted.
+    async fn stats(&self) -> anyhow::Result<BrokerStats>;
+
+    /// A round trip through the broker, fails once it is gone.
+    async fn health_check(&self) -> anyhow::Result<()>;
+
+    /// The server is going away, clients are asked to come back after `reconnect_after`.
+    async fn shutdown(&self, reconnect_after: Duration) -> anyhow::Result<()>;
+
+    /// Everyone the broker holds, where they connected from and whom they are with.
+    async fn inventory(&self) -> anyhow::Result<Inventory>;
+
+    /// Force `id` to disconnect, its correspondent is told it left. `false` if there is no
+    /// such peer.
+    async fn kick(&self, id: PeerId) -> anyhow::Result<bool>;
+
+    /// A [`crate::WsProtocol::Notice`] for everyone, returns how many peers it reached.
+    async fn broadcast(&self, message: String) -> anyhow::Result<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: &str) -> PeerId {
+        serde_json::from_value(id.into()).unwrap()
+    }
+
+    fn sorted(ids: Vec<PeerId>) -> Vec<String> {
+        let mut ids = ids.iter().map(PeerId::to_string).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn take_answers_once() {
+        let mut pending = PendingRequests::new(Duration::from_secs(30));
+        pending.insert(id("alice"), id("bob"));
+        assert!(!pending.take(&id("bob"), &id("alice")));
+        assert!(!pending.take(&id("alice"), &id("carol")));
+        assert!(pending.take(&id("alice"), &id("bob")));
+        assert!(!pending.take(&id("alice"), &id("bob")));
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut pending = PendingRequests::new(Duration::from_secs(30));
+        pending.insert(id("alice"), id("bob"));
+        pending.insert(id("alice"), id("carol"));
+        assert!(!pending.take(&id("alice"), &id("bob")));
+        assert!(pending.take(&id("alice"), &id("carol")));
+    }
+
+    #[test]
+    fn take_expired() {
+        let mut pending = PendingRequests::new(Duration::from_secs(30));
+        pending.insert(id("alice"), id("bob"));
+        pending.set_timeout(Duration::ZERO);
+        pending.insert(id("carol"), id("bob"));
+        assert_eq!(pending.take_expired(), vec![(id("carol"), id("bob"))]);
+        assert!(pending.take_expired().is_empty());
+        assert!(pending.take(&id("alice"), &id("bob")));
+    }
+
+    #[test]
+    fn take_involving() {
+        let mut pending = PendingRequests::new(Duration::from_secs(30));
+        pending.insert(id("alice"), id("bob"));
+        pending.insert(id("carol"), id("bob"));
+        pending.insert(id("bob"), id("dave"));
+        pending.insert(id("erin"), id("dave"));
+        assert_eq!(
+            sorted(pending.take_involving(&id("bob"))),
+            ["alice", "carol"]
+        );
+        // nobody waits for bob, and bob waits for nobody
+        assert!(!pending.take(&id("bob"), &id("dave")));
+        assert!(pending.take(&id("erin"), &id("dave")));
+        assert!(pending.take_involving(&id("bob")).is_empty());
+    }
+
+    fn code(codes: &mut PairingCodes, owner: &str) -> ConnectTo {
+        let (code, _) = codes.issue(&id(owner)).unwrap();
+        ConnectTo::Code(code)
+    }
+
+    #[test]
+    fn codes_are_used_up() {
+        let mut codes = PairingCodes::new(Duration::from_secs(120));
+        let alice = code(&mut codes, "alice");
+        assert_eq!(codes.resolve(&alice), Ok(id("alice")));
+        // until a request made with it got through
+        assert_eq!(codes.resolve(&alice), Ok(id("alice")));
+        codes.use_up(&alice);
+        assert_eq!(codes.resolve(&alice), Err(ConnectError::NotFound));
+
+        let bob = ConnectTo::Id(id("bob"));
+        codes.use_up(&bob);
+        assert_eq!(codes.resolve(&bob), Ok(id("bob")));
+    }
+
+    #[test]
+    fn codes_expire() {
+        let mut codes = PairingCodes::new(Duration::from_secs(120));
+        let alice = code(&mut codes, "alice");
+        codes.set_ttl(Duration::ZERO);
+        let bob = code(&mut codes, "bob");
+        assert_eq!(codes.resolve(&bob), Err(ConnectError::NotFound));
+
+        codes.expire();
+        assert_eq!(codes.codes.len(), 1);
+        assert_eq!(codes.resolve(&alice), Ok(id("alice")));
+    }
+
+    #[test]
+    fn new_code_replaces_old() {
+        let mut codes = PairingCodes::new(Duration::from_secs(120));
+        let first = code(&mut codes, "alice");
+        let bob = code(&mut codes, "bob");
+        let second = code(&mut codes, "alice");
+        assert_eq!(codes.resolve(&first), Err(ConnectError::NotFound));
+        assert_eq!(codes.resolve(&second), Ok(id("alice")));
+        assert_eq!(codes.resolve(&bob), Ok(id("bob")));
+    }
+}
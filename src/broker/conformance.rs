@@ -0,0 +1,662 @@
+//! The [`SignalingBroker`] contract, checked against every implementation.
+//!
+//! Each backend implements [`Conformance`] in its tests and instantiates the cases with
+//! [`conformance_tests!`]. The peers are the backend's own, their clients are channels.
+
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use futures::{channel::mpsc as client, StreamExt as _};
+use serde_json::json;
+use tokio::{sync::mpsc, time};
+
+use std::{any::Any, net::SocketAddr, time::Duration};
+
+use crate::{
+    ws_protocol::{
+        unsupported_version, Capability, ErrorCode, PROTOCOL_VERSION, UNANNOUNCED_VERSION,
+    },
+    ConnectError, ConnectTo, PeerId, RoomId, WsProtocol,
+};
+
+use super::{PeerInfo, PeerState, SignalingBroker};
+
+/// how long a peer gets to hear what it is expected to hear
+const PATIENCE: Duration = Duration::from_secs(2);
+
+/// how long a peer is listened to that is expected to hear nothing
+const SILENCE: Duration = Duration::from_millis(200);
+
+/// what the brokers under test are configured with, unanswered requests fail after it
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// how long it may take a broker to notice a request ran out
+const EXPIRY: Duration = Duration::from_secs(3);
+
+/// What a test client says to its peer.
+pub type Client = client::UnboundedSender<Result<Message, axum::Error>>;
+
+/// A peer the suite can reach the broker as.
+pub struct TestPeer<P> {
+    pub id: PeerId,
+    pub peer: P,
+    /// what its client says
+    client: Client,
+    /// the text its client is sent
+    inbox: mpsc::UnboundedReceiver<String>,
+    /// whatever keeps the peer running
+    _running: Box<dyn Any + Send>,
+}
+
+impl<P> TestPeer<P> {
+    /// Takes the id from the welcome `peer` sends first on `sent`.
+    pub async fn welcomed(
+        peer: P,
+        client: Client,
+        mut sent: client::UnboundedReceiver<Message>,
+        running: impl Any + Send,
+    ) -> Self {
+        let id = match time::timeout(PATIENCE, sent.next()).await {
+            Ok(Some(Message::Text(text))) => match serde_json::from_str(&text) {
+                Ok(WsProtocol::Welcome { id, .. }) => id,
+                other => panic!("expected a welcome, got {:?}", other),
+            },
+            other => panic!("expected a welcome, got {:?}", other),
+        };
+        let (to_client, inbox) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(message) = sent.next().await {
+                let Message::Text(text) = message else {
+                    continue;
+                };
+                if to_client.send(text.to_string()).is_err() {
+                    break;
+                }
+            }
+        });
+        TestPeer {
+            id,
+            peer,
+            client,
+            inbox,
+            _running: Box::new(running),
+        }
+    }
+
+    /// closes the websocket for good
+    fn hangs_up(&self) {
+        let close = Message::Close(Some(CloseFrame {
+            code: close_code::NORMAL,
+            reason: "bye".into(),
+        }));
+        assert!(self.client.unbounded_send(Ok(close)).is_ok());
+    }
+
+    fn says(&self, message: impl ToString) {
+        let said = self
+            .client
+            .unbounded_send(Ok(Message::text(message.to_string())));
+        assert!(said.is_ok(), "{} is not listening", self.id);
+    }
+
+    async fn hears_text(&mut self) -> String {
+        self.hears_text_within(PATIENCE).await
+    }
+
+    async fn hears_text_within(&mut self, patience: Duration) -> String {
+        match time::timeout(patience, self.inbox.recv()).await {
+            Ok(Some(text)) => text,
+            Ok(None) => panic!("{} is gone", self.id),
+            Err(_) => panic!("{} heard nothing within {:?}", self.id, patience),
+        }
+    }
+
+    async fn hears(&mut self) -> WsProtocol {
+        self.hears_within(PATIENCE).await
+    }
+
+    async fn hears_within(&mut self, patience: Duration) -> WsProtocol {
+        let text = self.hears_text_within(patience).await;
+        match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(error) => panic!("{} heard {:?} ({})", self.id, text, error),
+        }
+    }
+
+    async fn hears_nothing(&mut self) {
+        if let Ok(Some(message)) = time::timeout(SILENCE, self.inbox.recv()).await {
+            panic!("{} unexpectedly heard {}", self.id, message);
+        }
+    }
+}
+
+/// How the suite drives a broker implementation.
+pub trait Conformance: SignalingBroker<Peer: Clone> + Sized {
+    /// A running peer, admitted by the broker, whose client hasn't said hello yet.
+    async fn spawn_peer(&self) -> TestPeer<Self::Peer>;
+
+    /// A peer that has gone away without telling the broker.
+    async fn dead_peer(&self) -> Self::Peer;
+}
+
+/// A running peer whose client speaks everything.
+async fn greeted<B: Conformance>(broker: &B) -> TestPeer<B::Peer> {
+    let mut peer = broker.spawn_peer().await;
+    peer.says(WsProtocol::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: vec![
+            Capability::Resume,
+            Capability::Rooms,
+            Capability::Signaling,
+            Capability::Binary,
+            Capability::PairingCodes,
+        ],
+    });
+    assert!(matches!(peer.hears().await, WsProtocol::Hello { .. }));
+    peer
+}
+
+fn info() -> PeerInfo {
+    PeerInfo::new(SocketAddr::from(([127, 0, 0, 1], 4711)), None)
+}
+
+fn peer_id(id: &str) -> PeerId {
+    serde_json::from_value(id.into()).unwrap()
+}
+
+fn room(name: &str) -> RoomId {
+    serde_json::from_value(name.into()).unwrap()
+}
+
+fn to(peer: &TestPeer<impl Sized>) -> ConnectTo {
+    ConnectTo::Id(peer.id.clone())
+}
+
+/// `peer` joins `name`, which it is told about.
+async fn join<B: Conformance>(broker: &B, peer: &mut TestPeer<B::Peer>, name: &str) {
+    broker
+        .join(room(name), peer.id.clone(), peer.peer.clone())
+        .await
+        .unwrap();
+    assert!(
+        matches!(peer.hears().await, WsProtocol::Joined { room, .. } if room == self::room(name))
+    );
+}
+
+/// `peer` asks to join `name` and is told who is in it already.
+async fn enters<P>(peer: &mut TestPeer<P>, name: &str) -> Vec<PeerId> {
+    peer.says(WsProtocol::Join(room(name)));
+    match peer.hears().await {
+        WsProtocol::Joined { room, members } if room == self::room(name) => members,
+        other => panic!("{} expected to join {}, heard {}", peer.id, name, other),
+    }
+}
+
+/// `peer` hears that `other` came into `name`.
+async fn hears_joined<P>(peer: &mut TestPeer<P>, name: &str, other: &TestPeer<P>) {
+    assert!(matches!(
+        peer.hears().await,
+        WsProtocol::PeerJoined { room, peer } if room == self::room(name) && peer == other.id
+    ));
+}
+
+/// `asking` asks `asked`, which hears about it and accepts.
+async fn pair<B: Conformance>(
+    broker: &B,
+    asking: &mut TestPeer<B::Peer>,
+    asked: &mut TestPeer<B::Peer>,
+) {
+    broker.connect(asking.id.clone(), to(asked)).await.unwrap();
+    assert!(matches!(
+        asked.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == asking.id
+    ));
+    broker
+        .respond(asked.id.clone(), asking.id.clone(), true)
+        .await
+        .unwrap();
+    assert!(matches!(asking.hears().await, WsProtocol::Connected(id) if id == asked.id));
+    assert!(matches!(asked.hears().await, WsProtocol::Connected(id) if id == asking.id));
+}
+
+pub async fn admit_hands_out_unique_ids<B: Conformance>(broker: B) {
+    let mut peers = Vec::new();
+    for _ in 0..8 {
+        peers.push(greeted(&broker).await);
+    }
+    let mut ids = peers.iter().map(|peer| peer.id.clone()).collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.to_string());
+    ids.dedup();
+    assert_eq!(ids.len(), peers.len());
+}
+
+pub async fn hello_comes_first<B: Conformance>(broker: B) {
+    let mut a = broker.spawn_peer().await;
+    a.says(WsProtocol::RequestCode);
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::Bye { reason, .. } if reason == unsupported_version(UNANNOUNCED_VERSION)
+    ));
+}
+
+pub async fn capabilities_are_negotiated<B: Conformance>(broker: B) {
+    let mut a = broker.spawn_peer().await;
+    a.says(WsProtocol::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: vec![Capability::Signaling],
+    });
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::Hello { capabilities, .. } if capabilities == [Capability::Signaling]
+    ));
+
+    a.says(WsProtocol::Join(room("lobby")));
+    a.says(WsProtocol::RequestCode);
+    let binary = Message::Binary(vec![1, 2, 3].into());
+    assert!(a.client.unbounded_send(Ok(binary)).is_ok());
+    for _ in 0..3 {
+        assert!(matches!(
+            a.hears().await,
+            WsProtocol::Error {
+                code: ErrorCode::NotNegotiated,
+                ..
+            }
+        ));
+    }
+    let stats = broker.stats().await.unwrap();
+    assert_eq!(stats.room_members, 0);
+}
+
+pub async fn self_connect<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let result = broker.connect(a.id.clone(), to(&a)).await;
+    assert_eq!(result, Err(ConnectError::SelfConnect));
+}
+
+pub async fn unknown_peer<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let nobody = ConnectTo::Id(peer_id("nobody-at-all"));
+    let result = broker.connect(a.id.clone(), nobody).await;
+    assert_eq!(result, Err(ConnectError::NotFound));
+}
+
+pub async fn dead_peer_is_dropped<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let dead = peer_id("dead-peer");
+    let peer = broker.dead_peer().await;
+    broker.register(dead.clone(), peer, info()).await.unwrap();
+
+    let result = broker.connect(a.id.clone(), dead.clone().into()).await;
+    assert_eq!(result, Err(ConnectError::NotRunning));
+    let result = broker.connect(a.id.clone(), dead.into()).await;
+    assert_eq!(result, Err(ConnectError::NotFound));
+}
+
+pub async fn accepter_gone<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let c = greeted(&broker).await;
+    broker.connect(a.id.clone(), to(&b)).await.unwrap();
+    assert!(matches!(
+        b.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == a.id
+    ));
+    // b goes away between hearing the request and accepting it
+    let dead = broker.dead_peer().await;
+    broker.register(b.id.clone(), dead, info()).await.unwrap();
+
+    let result = broker.respond(b.id.clone(), a.id.clone(), true).await;
+    assert_eq!(
+        result.map_err(|error| error.code()),
+        Err(ErrorCode::Internal)
+    );
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::Error {
+            code: ErrorCode::Internal,
+            ..
+        }
+    ));
+
+    // still in the pool
+    broker.connect(c.id.clone(), to(&a)).await.unwrap();
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == c.id
+    ));
+}
+
+pub async fn room_member_is_busy<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    join(&broker, &mut b, "lobby").await;
+
+    let result = broker.connect(a.id.clone(), to(&b)).await;
+    assert_eq!(result, Err(ConnectError::AlreadyPaired));
+}
+
+pub async fn room_fan_out<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut c = greeted(&broker).await;
+    let mut elsewhere = greeted(&broker).await;
+    assert!(enters(&mut a, "lobby").await.is_empty());
+    assert_eq!(enters(&mut b, "lobby").await, vec![a.id.clone()]);
+    hears_joined(&mut a, "lobby", &b).await;
+    let mut members = enters(&mut c, "lobby").await;
+    members.sort_by_key(|id| id.to_string());
+    let mut expected = vec![a.id.clone(), b.id.clone()];
+    expected.sort_by_key(|id| id.to_string());
+    assert_eq!(members, expected);
+    hears_joined(&mut a, "lobby", &c).await;
+    hears_joined(&mut b, "lobby", &c).await;
+    enters(&mut elsewhere, "attic").await;
+
+    // whatever isn't protocol reaches everyone else in the room
+    a.says("hello lobby");
+    assert_eq!(b.hears_text().await, "hello lobby");
+    assert_eq!(c.hears_text().await, "hello lobby");
+    a.hears_nothing().await;
+    elsewhere.hears_nothing().await;
+
+    let payload = json!({ "for": "c only" });
+    a.says(WsProtocol::SendTo {
+        to: c.id.clone(),
+        payload: payload.clone(),
+    });
+    assert!(matches!(
+        c.hears().await,
+        WsProtocol::ReceivedFrom { from, payload: received } if from == a.id && received == payload
+    ));
+    b.hears_nothing().await;
+}
+
+pub async fn leaving_a_room_is_announced<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut c = greeted(&broker).await;
+    let d = greeted(&broker).await;
+    enters(&mut a, "lobby").await;
+    enters(&mut b, "lobby").await;
+    hears_joined(&mut a, "lobby", &b).await;
+    enters(&mut c, "lobby").await;
+    hears_joined(&mut a, "lobby", &c).await;
+    hears_joined(&mut b, "lobby", &c).await;
+
+    b.says(WsProtocol::Leave);
+    for member in [&mut a, &mut c].iter_mut() {
+        assert!(matches!(
+            member.hears().await,
+            WsProtocol::PeerLeft { room, peer } if room == self::room("lobby") && peer == b.id
+        ));
+    }
+    a.says("still here?");
+    assert_eq!(c.hears_text().await, "still here?");
+    b.hears_nothing().await;
+
+    // back in the pool
+    broker.connect(d.id.clone(), to(&b)).await.unwrap();
+    assert!(matches!(
+        b.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == d.id
+    ));
+
+    // hanging up leaves too
+    c.hangs_up();
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::PeerLeft { room, peer } if room == self::room("lobby") && peer == c.id
+    ));
+}
+
+pub async fn paired_peer_is_busy<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let c = greeted(&broker).await;
+    pair(&broker, &mut a, &mut b).await;
+
+    let result = broker.connect(c.id.clone(), to(&b)).await;
+    assert_eq!(result, Err(ConnectError::AlreadyPaired));
+}
+
+pub async fn rejected_request<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    broker.connect(a.id.clone(), to(&b)).await.unwrap();
+    assert!(matches!(
+        b.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == a.id
+    ));
+    broker
+        .respond(b.id.clone(), a.id.clone(), false)
+        .await
+        .unwrap();
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::Error { code, .. } if code == ConnectError::Rejected.code()
+    ));
+
+    // answered already
+    let result = broker.respond(b.id.clone(), a.id.clone(), true).await;
+    assert_eq!(result, Err(ConnectError::NoRequest));
+}
+
+pub async fn unanswered_request_times_out<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    broker.connect(a.id.clone(), to(&b)).await.unwrap();
+    assert!(matches!(
+        b.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == a.id
+    ));
+    assert!(matches!(
+        a.hears_within(CONNECT_TIMEOUT + EXPIRY).await,
+        WsProtocol::Error { code, .. } if code == ConnectError::TimedOut.code()
+    ));
+
+    // too late to accept
+    let result = broker.respond(b.id.clone(), a.id.clone(), true).await;
+    assert_eq!(result, Err(ConnectError::NoRequest));
+}
+
+pub async fn correspondent_leaving<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut c = greeted(&broker).await;
+    pair(&broker, &mut a, &mut b).await;
+
+    b.hangs_up();
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::Bye { reason, .. } if reason == "disconnected"
+    ));
+
+    // back in the pool
+    broker.connect(c.id.clone(), to(&a)).await.unwrap();
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == c.id
+    ));
+    c.hears_nothing().await;
+}
+
+pub async fn registering_again_replaces<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut replacement = greeted(&broker).await;
+    broker
+        .register(b.id.clone(), replacement.peer.clone(), info())
+        .await
+        .unwrap();
+
+    broker.connect(a.id.clone(), to(&b)).await.unwrap();
+    assert!(matches!(
+        replacement.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == a.id
+    ));
+    b.hears_nothing().await;
+}
+
+pub async fn pairing_code_is_single_use<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let c = greeted(&broker).await;
+    let (code, _) = broker.pairing_code(b.id.clone()).await.unwrap();
+
+    broker
+        .connect(a.id.clone(), ConnectTo::Code(code.clone()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        b.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == a.id
+    ));
+    let result = broker.connect(c.id.clone(), ConnectTo::Code(code)).await;
+    assert_eq!(result, Err(ConnectError::NotFound));
+}
+
+pub async fn pairing_code_outlives_failed_requests<B: Conformance>(broker: B) {
+    let a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let (code, _) = broker.pairing_code(b.id.clone()).await.unwrap();
+
+    let result = broker
+        .connect(b.id.clone(), ConnectTo::Code(code.clone()))
+        .await;
+    assert_eq!(result, Err(ConnectError::SelfConnect));
+    broker
+        .connect(a.id.clone(), ConnectTo::Code(code))
+        .await
+        .unwrap();
+    assert!(matches!(
+        b.hears().await,
+        WsProtocol::ConnectRequest { from, .. } if from == a.id
+    ));
+}
+
+pub async fn kick<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    assert!(broker.kick(a.id.clone()).await.unwrap());
+    assert!(matches!(
+        a.hears().await,
+        WsProtocol::Bye { reason, .. } if reason == "kicked"
+    ));
+    assert!(!broker.kick(peer_id("nobody-at-all")).await.unwrap());
+}
+
+pub async fn inventory<B: Conformance>(broker: B) {
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let c = greeted(&broker).await;
+    pair(&broker, &mut a, &mut b).await;
+
+    let inventory = broker.inventory().await.unwrap();
+    let state = |id: &PeerId| {
+        inventory
+            .peers
+            .iter()
+            .find(|summary| summary.id == *id)
+            .map(|summary| summary.state)
+    };
+    assert!(matches!(state(&a.id), Some(PeerState::Paired)));
+    assert!(matches!(state(&b.id), Some(PeerState::Paired)));
+    assert!(matches!(state(&c.id), Some(PeerState::Unpaired)));
+    assert!(inventory
+        .pairs
+        .iter()
+        .any(|pair| pair.contains(&a.id) && pair.contains(&b.id)));
+}
+
+pub async fn stats<B: Conformance>(broker: B) {
+    let _pooled = greeted(&broker).await;
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut member = greeted(&broker).await;
+    pair(&broker, &mut a, &mut b).await;
+    enters(&mut member, "lobby").await;
+
+    let stats = broker.stats().await.unwrap();
+    assert_eq!((stats.unpaired, stats.pairs, stats.room_members), (1, 1, 1));
+}
+
+pub async fn health_check<B: Conformance>(broker: B) {
+    broker.health_check().await.unwrap();
+}
+
+pub async fn broadcast_reaches_everyone<B: Conformance>(broker: B) {
+    let mut pooled = greeted(&broker).await;
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut member = greeted(&broker).await;
+    pair(&broker, &mut a, &mut b).await;
+    enters(&mut member, "lobby").await;
+
+    let reached = broker.broadcast("back in five".into()).await.unwrap();
+    assert_eq!(reached, 4);
+    for peer in [&mut pooled, &mut a, &mut b, &mut member].iter_mut() {
+        assert!(matches!(
+            peer.hears().await,
+            WsProtocol::Notice { message } if message == "back in five"
+        ));
+    }
+}
+
+pub async fn shutdown_reaches_everyone<B: Conformance>(broker: B) {
+    let mut pooled = greeted(&broker).await;
+    let mut a = greeted(&broker).await;
+    let mut b = greeted(&broker).await;
+    let mut member = greeted(&broker).await;
+    pair(&broker, &mut a, &mut b).await;
+    join(&broker, &mut member, "lobby").await;
+
+    broker.shutdown(Duration::from_secs(5)).await.unwrap();
+    for peer in [&mut pooled, &mut a, &mut b, &mut member].iter_mut() {
+        assert!(matches!(
+            peer.hears().await,
+            WsProtocol::Bye {
+                reconnect_after_secs: Some(5),
+                ..
+            }
+        ));
+    }
+}
+
+/// One test per case, `$run` hands it a broker of the backend.
+macro_rules! conformance_tests {
+    ($run:path) => {
+        conformance_tests!(
+            $run;
+            admit_hands_out_unique_ids,
+            hello_comes_first,
+            capabilities_are_negotiated,
+            self_connect,
+            unknown_peer,
+            dead_peer_is_dropped,
+            accepter_gone,
+            room_member_is_busy,
+            room_fan_out,
+            leaving_a_room_is_announced,
+            paired_peer_is_busy,
+            rejected_request,
+            unanswered_request_times_out,
+            correspondent_leaving,
+            registering_again_replaces,
+            pairing_code_is_single_use,
+            pairing_code_outlives_failed_requests,
+            kick,
+            inventory,
+            stats,
+            health_check,
+            broadcast_reaches_everyone,
+            shutdown_reaches_everyone
+        );
+    };
+    ($run:path; $($case:ident),*) => {
+        $(
+            #[test]
+            fn $case() {
+                $run($crate::broker::conformance::$case);
+            }
+        )*
+    };
+}
+
+pub(crate) use conformance_tests;
@@ -0,0 +1,54 @@
+use clap::{Parser, ValueEnum as _};
+
+use std::path::PathBuf;
+
+use crate::Backend;
+
+/// Settings are read from the config file, then the environment, then these flags.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML or YAML file to read the configuration from
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+
+    /// print the effective configuration and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// address to listen on
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// port to listen on
+    #[arg(long, short)]
+    pub port: Option<u16>,
+
+    /// broker implementation serving the websocket
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+
+    /// serve plain http, e.g. behind a tls terminating proxy
+    #[arg(long)]
+    pub no_tls: bool,
+}
+
+impl Cli {
+    /// Config keys set on the command line.
+    pub fn overrides(&self) -> Vec<(&'static str, config::Value)> {
+        let mut overrides = Vec::new();
+        if let Some(host) = &self.host {
+            overrides.push(("server.host", host.as_str().into()));
+        }
+        if let Some(port) = self.port {
+            overrides.push(("server.port", port.into()));
+        }
+        if let Some(backend) = self.backend.and_then(|backend| backend.to_possible_value()) {
+            overrides.push(("server.backend", backend.get_name().into()));
+        }
+        if self.no_tls {
+            overrides.push(("server.tls.enabled", false.into()));
+        }
+        overrides
+    }
+}
@@ -0,0 +1,9 @@
+//! Wall clock time as it goes on the wire, in seconds since the unix epoch.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
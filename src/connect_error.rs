@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+use crate::ws_protocol::{ErrorCode, WsProtocol};
+
+/// Reasons why a broker could not connect two peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectError {
+    SelfConnect,
+    NotFound,
+    NotRunning,
+    AlreadyPaired,
+    NotRegistered,
+    DeliveryFailed,
+    /// `to` declined
+    Rejected,
+    /// `to` didn't answer in time
+    TimedOut,
+    /// accept or reject without a pending request
+    NoRequest,
+}
+
+impl ConnectError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ConnectError::SelfConnect => ErrorCode::SelfConnect,
+            ConnectError::NotFound => ErrorCode::UnknownPeer,
+            ConnectError::NotRunning => ErrorCode::PeerGone,
+            ConnectError::AlreadyPaired => ErrorCode::PeerBusy,
+            ConnectError::Rejected => ErrorCode::Rejected,
+            ConnectError::TimedOut => ErrorCode::TimedOut,
+            ConnectError::NoRequest => ErrorCode::NoRequest,
+            ConnectError::NotRegistered | ConnectError::DeliveryFailed => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NotRunning => write!(f, "peer not running"),
+            ConnectError::NotRegistered => write!(f, "requesting peer is not registered"),
+            ConnectError::DeliveryFailed => write!(f, "failed to connect to peer"),
+            // the client's to know, in the same words
+            ConnectError::SelfConnect
+            | ConnectError::NotFound
+            | ConnectError::AlreadyPaired
+            | ConnectError::Rejected
+            | ConnectError::TimedOut
+            | ConnectError::NoRequest => f.write_str(self.code().message()),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<ConnectError> for WsProtocol {
+    fn from(error: ConnectError) -> Self {
+        WsProtocol::Error {
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [ConnectError; 9] = [
+        ConnectError::SelfConnect,
+        ConnectError::NotFound,
+        ConnectError::NotRunning,
+        ConnectError::AlreadyPaired,
+        ConnectError::NotRegistered,
+        ConnectError::DeliveryFailed,
+        ConnectError::Rejected,
+        ConnectError::TimedOut,
+        ConnectError::NoRequest,
+    ];
+
+    #[test]
+    fn codes() {
+        let codes = ALL.map(|error| error.code());
+        assert_eq!(
+            codes,
+            [
+                ErrorCode::SelfConnect,
+                ErrorCode::UnknownPeer,
+                ErrorCode::PeerGone,
+                ErrorCode::PeerBusy,
+                ErrorCode::Internal,
+                ErrorCode::Internal,
+                ErrorCode::Rejected,
+                ErrorCode::TimedOut,
+                ErrorCode::NoRequest,
+            ]
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            ConnectError::SelfConnect.to_string(),
+            "attempted to connect to self"
+        );
+        assert_eq!(ConnectError::NotFound.to_string(), "peer not found");
+        assert_eq!(
+            ConnectError::NotRegistered.to_string(),
+            "requesting peer is not registered"
+        );
+        assert_eq!(
+            ConnectError::TimedOut.to_string(),
+            "peer did not answer in time"
+        );
+        assert_eq!(ConnectError::NotRunning.to_string(), "peer not running");
+        for error in ALL {
+            if !matches!(error.code(), ErrorCode::Internal | ErrorCode::PeerGone) {
+                assert_eq!(error.to_string(), error.code().message());
+            }
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        let serialized = ALL.map(|error| serde_json::to_string(&error).unwrap());
+        assert_eq!(
+            serialized,
+            [
+                r#""selfConnect""#,
+                r#""notFound""#,
+                r#""notRunning""#,
+                r#""alreadyPaired""#,
+                r#""notRegistered""#,
+                r#""deliveryFailed""#,
+                r#""rejected""#,
+                r#""timedOut""#,
+                r#""noRequest""#,
+            ]
+        );
+        for error in ALL {
+            let json = serde_json::to_string(&error).unwrap();
+            assert_eq!(serde_json::from_str::<ConnectError>(&json).unwrap(), error);
+        }
+    }
+
+    #[test]
+    fn as_protocol_error() {
+        let message = WsProtocol::from(ConnectError::AlreadyPaired);
+        assert_eq!(
+            message.to_string(),
+            r#"{"error":{"code":"peerBusy","message":"peer is already connected"}}"#
+        );
+        for error in ALL {
+            let WsProtocol::Error { code, message } = WsProtocol::from(error) else {
+                panic!("{:?} is not an error", error);
+            };
+            assert_eq!(code, error.code());
+            assert_eq!(message, error.to_string());
+        }
+    }
+}
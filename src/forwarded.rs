@@ -0,0 +1,146 @@
+//! The address of the client behind a reverse proxy.
+//!
+//! Proxies append the address they were connected from to `Forwarded` (RFC 7239) or
+//! `X-Forwarded-For`. Anybody can send those headers, so the list is only followed from the
+//! right as long as the hop that wrote the entry is a trusted proxy.
+
+use axum::http::{header, HeaderMap, HeaderName};
+
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// Whom `remote` forwards for, `remote` itself unless it is one of the `trusted` proxies.
+///
+/// `Forwarded` is preferred over `X-Forwarded-For`, the port is 0 unless the proxy told it.
+pub fn client_addr(trusted: &[IpAddr], headers: &HeaderMap, remote: SocketAddr) -> SocketAddr {
+    if !trusted.contains(&remote.ip()) {
+        return remote;
+    }
+    let forwarded = values(headers, header::FORWARDED)
+        .map(|hops| {
+            hops.map(|hop| for_param(hop).and_then(node))
+                .collect::<Vec<_>>()
+        })
+        .or_else(|| values(headers, X_FORWARDED_FOR).map(|hops| hops.map(ip).collect()))
+        .unwrap_or_default();
+
+    let mut client = remote;
+    for hop in forwarded.into_iter().rev() {
+        if !trusted.contains(&client.ip()) {
+            break;
+        }
+        // obfuscated, `unknown` or garbage, the proxy that wrote it is as close as it gets
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+    }
+    client
+}
+
+/// The comma separated elements of every `name` header, `None` if there is none.
+fn values(headers: &HeaderMap, name: HeaderName) -> Option<impl Iterator<Item = &str>> {
+    let mut values = headers.get_all(name).iter().peekable();
+    values.peek()?;
+    Some(
+        values
+            .map(|value| value.to_str().unwrap_or_default())
+            .flat_map(|value| value.split(','))
+            .map(str::trim),
+    )
+}
+
+/// `for=` of a `Forwarded` element such as `for=192.0.2.60;proto=http;by=203.0.113.43`
+fn for_param(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("for")
+            .then(|| value.trim_matches('"'))
+    })
+}
+
+/// `192.0.2.60`, `192.0.2.60:4711`, `[2001:db8::1]` or `[2001:db8::1]:4711`
+fn node(node: &str) -> Option<SocketAddr> {
+    node.parse()
+        .ok()
+        .or_else(|| ip(node.strip_prefix('[')?.strip_suffix(']')?))
+        .or_else(|| ip(node))
+}
+
+fn ip(ip: &str) -> Option<SocketAddr> {
+    Some(SocketAddr::new(ip.parse().ok()?, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROXY: &str = "10.0.0.2:51234";
+
+    fn trusted() -> Vec<IpAddr> {
+        vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()]
+    }
+
+    fn client(remote: &str, headers: &[(HeaderName, &str)]) -> String {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+            .collect();
+        client_addr(&trusted(), &headers, remote.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn untrusted_remote() {
+        let spoofed = [(X_FORWARDED_FOR, "192.0.2.60")];
+        assert_eq!(client("203.0.113.7:4711", &spoofed), "203.0.113.7:4711");
+    }
+
+    #[test]
+    fn no_headers() {
+        assert_eq!(client(PROXY, &[]), PROXY);
+    }
+
+    #[test]
+    fn x_forwarded_for() {
+        let headers = [(X_FORWARDED_FOR, "192.0.2.60")];
+        assert_eq!(client(PROXY, &headers), "192.0.2.60:0");
+        let ipv6 = [(X_FORWARDED_FOR, "2001:db8::1")];
+        assert_eq!(client(PROXY, &ipv6), "[2001:db8::1]:0");
+    }
+
+    #[test]
+    fn forwarded() {
+        let headers = [(header::FORWARDED, "for=192.0.2.60;proto=https;by=10.0.0.2")];
+        assert_eq!(client(PROXY, &headers), "192.0.2.60:0");
+        let quoted = [(header::FORWARDED, r#"For="[2001:db8::1]:4711""#)];
+        assert_eq!(client(PROXY, &quoted), "[2001:db8::1]:4711");
+        let preferred = [
+            (X_FORWARDED_FOR, "198.51.100.1"),
+            (header::FORWARDED, "for=192.0.2.60"),
+        ];
+        assert_eq!(client(PROXY, &preferred), "192.0.2.60:0");
+    }
+
+    #[test]
+    fn only_trusted_hops_are_followed() {
+        // the client made up the first entry
+        let chain = [(X_FORWARDED_FOR, "198.51.100.1, 192.0.2.60, 10.0.0.1")];
+        assert_eq!(client(PROXY, &chain), "192.0.2.60:0");
+        let split = [
+            (header::FORWARDED, "for=198.51.100.1, for=192.0.2.60"),
+            (header::FORWARDED, "for=10.0.0.1"),
+        ];
+        assert_eq!(client(PROXY, &split), "192.0.2.60:0");
+    }
+
+    #[test]
+    fn unusable_hops() {
+        let hidden = [(header::FORWARDED, "for=192.0.2.60, for=_hidden")];
+        assert_eq!(client(PROXY, &hidden), PROXY);
+        let unknown = [(X_FORWARDED_FOR, "192.0.2.60, 10.0.0.1, unknown")];
+        assert_eq!(client(PROXY, &unknown), PROXY);
+        let garbage = [(X_FORWARDED_FOR, "192.0.2.60, 10.0.0.1, nonsense")];
+        assert_eq!(client("10.0.0.1:80", &garbage), "10.0.0.1:80");
+    }
+}
@@ -0,0 +1,60 @@
+use std::{fmt, time::Duration};
+
+use crate::{relay, ws_protocol::IceServer, PeerId, ServerConfig};
+
+/// The ice servers offered to one client, resolved against the host it connected to.
+#[derive(Clone, Default)]
+pub struct IceServers {
+    stun: Option<String>,
+    turn: Option<TurnIssuer>,
+    /// from `server.ice_servers`
+    configured: Vec<IceServer>,
+}
+
+#[derive(Clone)]
+struct TurnIssuer {
+    urls: Vec<String>,
+    secret: String,
+    ttl: Duration,
+}
+
+impl IceServers {
+    pub fn new(server: &ServerConfig, requested_host: Option<&str>) -> Self {
+        let turn = &server.turn;
+        let urls = turn.urls(requested_host);
+        Self {
+            stun: server.stun.url(requested_host),
+            turn: (turn.enabled && !urls.is_empty()).then(|| TurnIssuer {
+                urls,
+                secret: turn.secret.clone(),
+                ttl: Duration::from_secs(turn.credential_ttl_secs),
+            }),
+            configured: server.ice_servers.clone(),
+        }
+    }
+
+    /// Everything a client needs to set up a peer connection, relay credentials are minted for `id`.
+    pub fn for_peer(&self, id: &PeerId) -> Vec<IceServer> {
+        let stun = self.stun.iter().map(|url| IceServer {
+            urls: vec![url.clone()],
+            ..IceServer::default()
+        });
+        let turn = self
+            .turn
+            .iter()
+            .map(|turn| relay::ice_server(turn.urls.clone(), &turn.secret, turn.ttl, id));
+        stun.chain(turn)
+            .chain(self.configured.iter().cloned())
+            .collect()
+    }
+}
+
+impl fmt::Debug for IceServers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IceServers")
+            .field("stun", &self.stun)
+            .field("turn", &self.turn.as_ref().map(|turn| &turn.urls))
+            .field("configured", &self.configured.len())
+            .finish()
+    }
+}
@@ -0,0 +1,207 @@
+//! Liveness of a single websocket.
+//!
+//! Browsers answer pings on their own, so a client that stays silent past the pong deadline
+//! is most likely gone, e.g. behind a half-open tcp connection.
+
+use std::time::{Duration, Instant};
+
+use crate::SessionConfig;
+
+/// Ping interval, pong deadline and idle timeout, see [`SessionConfig`].
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    idle_timeout: Option<Duration>,
+}
+
+impl From<&SessionConfig> for Timeouts {
+    fn from(session: &SessionConfig) -> Self {
+        let enabled = |secs: u64| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+        Self {
+            ping_interval: enabled(session.ping_interval_secs),
+            pong_timeout: Duration::from_secs(session.pong_timeout_secs),
+            idle_timeout: enabled(session.idle_timeout_secs),
+        }
+    }
+}
+
+/// What to do about the websocket, see [`Keepalive::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    Alive,
+    /// send a ping, its pong is expected within the deadline
+    Ping,
+    /// no answer to the last ping
+    Unresponsive,
+    /// the client has not sent anything but pongs for too long
+    Idle,
+}
+
+#[derive(Debug)]
+pub struct Keepalive {
+    timeouts: Timeouts,
+    /// any frame, pongs included
+    last_heard: Instant,
+    /// text or binary frames
+    last_active: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl Keepalive {
+    pub fn new(timeouts: Timeouts) -> Self {
+        let now = Instant::now();
+        Self {
+            timeouts,
+            last_heard: now,
+            last_active: now,
+            ping_sent: None,
+        }
+    }
+
+    /// How often [`Keepalive::check`] should be called, `None` if everything is turned off.
+    pub fn period(&self) -> Option<Duration> {
+        let Timeouts {
+            ping_interval,
+            pong_timeout,
+            idle_timeout,
+        } = self.timeouts;
+        let pings = ping_interval.map(|interval| interval.min(pong_timeout));
+        match (pings, idle_timeout) {
+            (Some(pings), Some(idle)) => Some(pings.min(idle)),
+            (pings, idle) => pings.or(idle),
+        }
+    }
+
+    /// The client sent a frame of any kind.
+    pub fn heard(&mut self) {
+        self.heard_at(Instant::now());
+    }
+
+    /// The client sent a text or binary frame.
+    pub fn active(&mut self) {
+        self.active_at(Instant::now());
+    }
+
+    pub fn check(&mut self) -> Check {
+        self.check_at(Instant::now())
+    }
+
+    fn heard_at(&mut self, now: Instant) {
+        self.last_heard = now;
+        self.ping_sent = None;
+    }
+
+    fn active_at(&mut self, now: Instant) {
+        self.heard_at(now);
+        self.last_active = now;
+    }
+
+    fn check_at(&mut self, now: Instant) -> Check {
+        if let Some(idle) = self.timeouts.idle_timeout {
+            if now.duration_since(self.last_active) >= idle {
+                return Check::Idle;
+            }
+        }
+        match (self.ping_sent, self.timeouts.ping_interval) {
+            (Some(sent), _) if now.duration_since(sent) >= self.timeouts.pong_timeout => {
+                Check::Unresponsive
+            }
+            (None, Some(interval)) if now.duration_since(self.last_heard) >= interval => {
+                self.ping_sent = Some(now);
+                Check::Ping
+            }
+            _ => Check::Alive,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts(ping_interval: u64, pong_timeout: u64, idle_timeout: u64) -> Timeouts {
+        Timeouts::from(&SessionConfig {
+            ping_interval_secs: ping_interval,
+            pong_timeout_secs: pong_timeout,
+            idle_timeout_secs: idle_timeout,
+            ..Default::default()
+        })
+    }
+
+    /// A keepalive and a clock that starts when it was created.
+    fn keepalive(timeouts: Timeouts) -> (Keepalive, impl Fn(u64) -> Instant) {
+        let keepalive = Keepalive::new(timeouts);
+        let start = keepalive.last_heard;
+        (keepalive, move |secs| start + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn pings_a_quiet_client() {
+        let (mut keepalive, at) = keepalive(timeouts(20, 10, 0));
+        assert_eq!(keepalive.check_at(at(19)), Check::Alive);
+        assert_eq!(keepalive.check_at(at(20)), Check::Ping);
+        // one ping per interval
+        assert_eq!(keepalive.check_at(at(25)), Check::Alive);
+        assert_eq!(keepalive.check_at(at(29)), Check::Alive);
+    }
+
+    #[test]
+    fn pong_in_time() {
+        let (mut keepalive, at) = keepalive(timeouts(20, 10, 0));
+        assert_eq!(keepalive.check_at(at(20)), Check::Ping);
+        keepalive.heard_at(at(25));
+        assert_eq!(keepalive.check_at(at(30)), Check::Alive);
+        assert_eq!(keepalive.check_at(at(44)), Check::Alive);
+        assert_eq!(keepalive.check_at(at(45)), Check::Ping);
+    }
+
+    #[test]
+    fn no_pong() {
+        let (mut keepalive, at) = keepalive(timeouts(20, 10, 0));
+        assert_eq!(keepalive.check_at(at(20)), Check::Ping);
+        assert_eq!(keepalive.check_at(at(30)), Check::Unresponsive);
+    }
+
+    #[test]
+    fn traffic_postpones_pings() {
+        let (mut keepalive, at) = keepalive(timeouts(20, 10, 0));
+        keepalive.active_at(at(15));
+        assert_eq!(keepalive.check_at(at(20)), Check::Alive);
+        assert_eq!(keepalive.check_at(at(35)), Check::Ping);
+    }
+
+    #[test]
+    fn pongs_are_not_activity() {
+        let (mut keepalive, at) = keepalive(timeouts(20, 10, 60));
+        assert_eq!(keepalive.check_at(at(20)), Check::Ping);
+        keepalive.heard_at(at(21));
+        assert_eq!(keepalive.check_at(at(41)), Check::Ping);
+        keepalive.heard_at(at(42));
+        assert_eq!(keepalive.check_at(at(60)), Check::Idle);
+    }
+
+    #[test]
+    fn activity_is_not_idle() {
+        let (mut keepalive, at) = keepalive(timeouts(0, 10, 60));
+        keepalive.active_at(at(50));
+        assert_eq!(keepalive.check_at(at(109)), Check::Alive);
+        assert_eq!(keepalive.check_at(at(110)), Check::Idle);
+    }
+
+    #[test]
+    fn turned_off() {
+        let (mut keepalive, at) = keepalive(timeouts(0, 10, 0));
+        assert_eq!(keepalive.period(), None);
+        assert_eq!(keepalive.check_at(at(3600)), Check::Alive);
+    }
+
+    #[test]
+    fn period() {
+        let secs = |timeouts| Keepalive::new(timeouts).period().map(|d| d.as_secs());
+        assert_eq!(secs(timeouts(20, 10, 0)), Some(10));
+        assert_eq!(secs(timeouts(5, 10, 0)), Some(5));
+        assert_eq!(secs(timeouts(20, 10, 3)), Some(3));
+        assert_eq!(secs(timeouts(0, 10, 60)), Some(60));
+    }
+}
@@ -1,42 +1,561 @@
-use tower_http::services::ServeDir;
+use clap::Parser as _;
 use tracing_subscriber::EnvFilter;
 
+use std::{
+    net::{AddrParseError, IpAddr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+};
+
 mod actors;
+mod admin;
+mod auth;
 mod basic;
+mod broker;
+mod cli;
+mod clock;
+mod connect_error;
+mod forwarded;
+mod ice;
+mod keepalive;
+mod metrics;
+mod pairing_code;
 mod peer_id;
+mod rate_limit;
+mod relay;
+mod resume_token;
+mod room_id;
 mod routes;
+mod server;
+mod shutdown;
+mod stun;
+mod tls;
 mod ws_protocol;
 
-pub use peer_id::PeerId;
-pub use ws_protocol::WsProtocol;
+pub use cli::Cli;
+pub use connect_error::ConnectError;
+pub use ice::IceServers;
+pub use pairing_code::{ConnectTo, PairingCode};
+pub use peer_id::{IdGenerator, PeerId};
+pub use resume_token::ResumeToken;
+pub use room_id::RoomId;
+pub use ws_protocol::{IceServer, WsProtocol};
+
+/// Which broker implementation serves the websocket.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, serde::Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// channel based broker, see `basic::Broker`
+    Basic,
+    /// actor based broker, see `actors::Broker`
+    #[default]
+    Actors,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// serve https, turn off behind a tls terminating proxy
+    pub enabled: bool,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// only accept clients with a certificate signed by this CA
+    pub client_ca: Option<PathBuf>,
+    /// how often the files are checked for changes, 0 disables reloading
+    pub reload_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cert: PathBuf::from("testcerts/cert.pem"),
+            key: PathBuf::from("testcerts/key.pem"),
+            client_ca: None,
+            reload_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct StunConfig {
+    pub enabled: bool,
+    /// udp port, bound on `server.host`
+    pub port: u16,
+    /// host clients reach the stun server by, defaults to the one they used for the websocket
+    pub public_host: Option<String>,
+}
+
+impl Default for StunConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            port: 3478,
+            public_host: None,
+        }
+    }
+}
+
+impl StunConfig {
+    /// The url advertised to a client that reached us via `requested_host`.
+    pub fn url(&self, requested_host: Option<&str>) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let host = self.public_host.as_deref().or(requested_host)?;
+        Some(format!("stun:{}:{}", host, self.port))
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct TurnConfig {
+    pub enabled: bool,
+    /// udp and tcp port, bound on `server.host`
+    pub port: u16,
+    /// also accept turn over tcp
+    pub tcp: bool,
+    /// address relayed traffic appears to come from, defaults to `server.host`
+    pub relay_ip: Option<IpAddr>,
+    /// host clients reach the relay by, defaults to the one they used for the websocket
+    pub public_host: Option<String>,
+    pub realm: String,
+    /// credentials handed to clients are derived from this, never printed
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// how long credentials handed to clients are valid
+    pub credential_ttl_secs: u64,
+}
+
+impl Default for TurnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 3479,
+            tcp: true,
+            relay_ip: None,
+            public_host: None,
+            realm: String::from("cast-me"),
+            secret: String::new(),
+            credential_ttl_secs: 3600,
+        }
+    }
+}
+
+impl TurnConfig {
+    /// The urls advertised to a client that reached us via `requested_host`.
+    pub fn urls(&self, requested_host: Option<&str>) -> Vec<String> {
+        let Some(host) = self.public_host.as_deref().or(requested_host) else {
+            return Vec::new();
+        };
+        let mut urls = vec![format!("turn:{}:{}?transport=udp", host, self.port)];
+        if self.tcp {
+            urls.push(format!("turn:{}:{}?transport=tcp", host, self.port));
+        }
+        urls
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// how long open websockets get to close after SIGINT or SIGTERM
+    pub drain_timeout_secs: u64,
+    /// clients are asked to reconnect after this long
+    pub reconnect_after_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 10,
+            reconnect_after_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// serve the admin api under `/admin`
+    pub enabled: bool,
+    /// expected as `Authorization: Bearer <token>`, never printed
+    #[serde(skip_serializing)]
+    pub token: String,
+}
+
+/// How tokens presented on the websocket upgrade are signed.
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, serde::Serialize)]
+pub enum JwtAlgorithm {
+    /// HMAC with `server.auth.secret`
+    #[default]
+    #[serde(rename = "HS256")]
+    Hs256,
+    /// RSA with the key in `server.auth.public_key`
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+impl JwtAlgorithm {
+    /// as in the `alg` header of a token
+    pub fn name(self) -> &'static str {
+        match self {
+            JwtAlgorithm::Hs256 => "HS256",
+            JwtAlgorithm::Rs256 => "RS256",
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// only accept websockets with a valid token
+    pub enabled: bool,
+    pub algorithm: JwtAlgorithm,
+    /// HS256 tokens are signed with this, never printed
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// PEM file with the RS256 public key
+    pub public_key: Option<PathBuf>,
+    /// required `iss` claim
+    pub issuer: Option<String>,
+    /// required `aud` claim
+    pub audience: Option<String>,
+    /// clock skew tolerated on `exp` and `nbf`
+    pub leeway_secs: u64,
+    /// cookie the token is taken from if there is no authorization header
+    pub cookie: String,
+    /// query parameter the token is taken from if there is neither header nor cookie
+    pub query_param: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithm: JwtAlgorithm::default(),
+            secret: String::new(),
+            public_key: None,
+            issuer: None,
+            audience: None,
+            leeway_secs: 30,
+            cookie: String::from("cast_me_token"),
+            query_param: String::from("token"),
+        }
+    }
+}
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// throttle websocket upgrades and connect requests
+    pub enabled: bool,
+    /// websocket upgrades per client address, refilled per minute
+    pub upgrades_per_minute: u32,
+    /// websocket upgrades per client address at once
+    pub upgrade_burst: u32,
+    /// connect requests per client address, refilled per minute
+    pub connects_per_minute: u32,
+    pub connect_burst: u32,
+    /// connect requests per websocket, refilled per minute
+    pub peer_connects_per_minute: u32,
+    pub peer_connect_burst: u32,
+    /// connect requests for unknown peers before the address is banned, 0 never bans
+    pub ban_after_misses: u32,
+    /// misses are counted within this window
+    pub miss_window_secs: u64,
+    /// how long a banned address is refused
+    pub ban_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            upgrades_per_minute: 30,
+            upgrade_burst: 10,
+            connects_per_minute: 30,
+            connect_burst: 10,
+            peer_connects_per_minute: 10,
+            peer_connect_burst: 5,
+            ban_after_misses: 20,
+            miss_window_secs: 600,
+            ban_secs: 900,
+        }
+    }
+}
+
+/// What new peer ids look like.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerIdStyle {
+    /// words joined by hyphens, e.g. `alpha-bravo`
+    #[default]
+    Words,
+    /// decimal digits
+    Pin,
+    /// lowercase base32 characters
+    Base32,
+    /// a full uuid, `length` is ignored
+    Uuid,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct PeerIdConfig {
+    pub style: PeerIdStyle,
+    /// words, digits or characters, 2 words, 6 digits and 8 characters if unset
+    pub length: Option<usize>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub stun: StunConfig,
+    #[serde(default)]
+    pub turn: TurnConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub peer_ids: PeerIdConfig,
+    /// further stun or turn servers handed to clients, e.g. a third party relay,
+    /// their credentials are never printed
+    #[serde(default, serialize_with = "without_credentials")]
+    pub ice_servers: Vec<IceServer>,
+    /// reverse proxies whose `Forwarded` or `X-Forwarded-For` tell the client address
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// `server.ice_servers` as printed
+fn without_credentials<S: serde::Serializer>(
+    servers: &[IceServer],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(servers.iter().map(|server| IceServer {
+        credential: None,
+        ..server.clone()
+    }))
 }
 
-#[derive(Debug, serde::Deserialize)]
+impl ServerConfig {
+    /// `host` with the given port, ipv6 included.
+    pub fn socket_addr(&self, port: u16) -> Result<SocketAddr, AddrParseError> {
+        Ok(SocketAddr::new(self.host.parse()?, port))
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// how long a dropped session can be resumed
+    pub resume_grace_secs: u64,
+    /// how long a websocket may stay silent before it is pinged, 0 disables pings
+    pub ping_interval_secs: u64,
+    /// how long to wait for the answer to a ping before the websocket is closed
+    pub pong_timeout_secs: u64,
+    /// close websockets without text or binary frames for this long, 0 disables it
+    pub idle_timeout_secs: u64,
+    /// how long a peer has to accept or reject a connect request
+    pub connect_timeout_secs: u64,
+    /// how long a pairing code can be used to connect
+    pub pairing_code_ttl_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            resume_grace_secs: 30,
+            ping_interval_secs: 20,
+            pong_timeout_secs: 10,
+            idle_timeout_secs: 0,
+            connect_timeout_secs: 30,
+            pairing_code_ttl_secs: 120,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Config {
     pub server: ServerConfig,
+    #[serde(default)]
+    pub session: SessionConfig,
     pub log_config: Option<String>,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, config::ConfigError> {
-        config::Config::builder()
-            .add_source(config::Environment::default())
-            .build()?
-            .try_deserialize()
+    /// Defaults, overridden by the config file, the environment and the command line, in that order.
+    pub fn load(cli: &Cli) -> Result<Self, config::ConfigError> {
+        let mut builder = config::Config::builder()
+            .set_default("server.host", "0.0.0.0")?
+            .set_default("server.port", 3030)?;
+        if let Some(path) = &cli.config {
+            builder = builder.add_source(config::File::from(path.as_path()));
+        }
+        builder = builder.add_source(config::Environment::default());
+        for (key, value) in cli.overrides() {
+            builder = builder.set_override(key, value)?;
+        }
+
+        let config: Self = builder.build()?.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), config::ConfigError> {
+        let invalid = |message: String| Err(config::ConfigError::Message(message));
+
+        if self.server.socket_addr(self.server.port).is_err() {
+            let host = &self.server.host;
+            return invalid(format!("server.host: {host} is not a valid ip address"));
+        }
+
+        let turn = &self.server.turn;
+        if turn.enabled {
+            if turn.secret.is_empty() {
+                return invalid(String::from("server.turn.secret: required by the relay"));
+            }
+            let relay_ip = turn.relay_ip.or_else(|| self.server.host.parse().ok());
+            if relay_ip.is_none_or(|ip: IpAddr| ip.is_unspecified()) {
+                return invalid(format!(
+                    "server.turn.relay_ip: required when listening on {}",
+                    self.server.host
+                ));
+            }
+            if self.server.stun.enabled && self.server.stun.port == turn.port {
+                return invalid(format!("server.turn.port: {} is used by stun", turn.port));
+            }
+        }
+
+        let admin = &self.server.admin;
+        if admin.enabled && admin.token.is_empty() {
+            return invalid(String::from(
+                "server.admin.token: required by the admin api",
+            ));
+        }
+
+        let auth = &self.server.auth;
+        if auth.enabled {
+            match (auth.algorithm, &auth.public_key) {
+                (JwtAlgorithm::Hs256, _) if auth.secret.is_empty() => {
+                    return invalid(String::from("server.auth.secret: required by HS256"));
+                }
+                (JwtAlgorithm::Rs256, None) => {
+                    return invalid(String::from("server.auth.public_key: required by RS256"));
+                }
+                (JwtAlgorithm::Rs256, Some(path)) if !path.is_file() => {
+                    let path = path.display();
+                    return invalid(format!("server.auth.public_key: {path} does not exist"));
+                }
+                _ => {}
+            }
+        }
+
+        let rate_limit = &self.server.rate_limit;
+        if rate_limit.enabled {
+            let rates = [
+                ("upgrades_per_minute", rate_limit.upgrades_per_minute),
+                ("upgrade_burst", rate_limit.upgrade_burst),
+                ("connects_per_minute", rate_limit.connects_per_minute),
+                ("connect_burst", rate_limit.connect_burst),
+                (
+                    "peer_connects_per_minute",
+                    rate_limit.peer_connects_per_minute,
+                ),
+                ("peer_connect_burst", rate_limit.peer_connect_burst),
+            ];
+            for (key, value) in rates {
+                if value == 0 {
+                    return invalid(format!("server.rate_limit.{key}: must be at least 1"));
+                }
+            }
+        }
+
+        if let Err(message) = IdGenerator::new(&self.server.peer_ids).check() {
+            return invalid(format!("server.peer_ids.length: {message}"));
+        }
+
+        let session = &self.session;
+        if session.ping_interval_secs > 0 && session.pong_timeout_secs == 0 {
+            return invalid(String::from(
+                "session.pong_timeout_secs: required when pinging",
+            ));
+        }
+        if session.connect_timeout_secs == 0 {
+            return invalid(String::from(
+                "session.connect_timeout_secs: must be at least 1",
+            ));
+        }
+        if session.pairing_code_ttl_secs == 0 {
+            return invalid(String::from(
+                "session.pairing_code_ttl_secs: must be at least 1",
+            ));
+        }
+
+        let tls = &self.server.tls;
+        if tls.enabled {
+            let files = [
+                ("server.tls.cert", Some(&tls.cert)),
+                ("server.tls.key", Some(&tls.key)),
+                ("server.tls.client_ca", tls.client_ca.as_ref()),
+            ];
+            for (key, path) in files.iter() {
+                if let Some(path) = path.filter(|path| !path.is_file()) {
+                    return invalid(format!("{key}: {} does not exist", path.display()));
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 #[tokio::main]
 #[tracing::instrument]
-async fn main() {
+async fn main() -> ExitCode {
     color_backtrace::install();
-    dotenv::dotenv().unwrap();
-    let config = Config::from_env().unwrap();
+    let cli = Cli::parse();
+
+    // a .env file is optional, but it must be readable if there is one
+    if let Err(error) = dotenv::dotenv() {
+        if !error.not_found() {
+            eprintln!("failed to read .env: {error}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("invalid configuration: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if cli.print_config {
+        match toml::to_string_pretty(&config) {
+            Ok(config) => {
+                print!("{config}");
+                return ExitCode::SUCCESS;
+            }
+            Err(error) => {
+                eprintln!("failed to print configuration: {error}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
 
     tracing_subscriber::fmt()
         // .pretty()
@@ -48,65 +567,9 @@ async fn main() {
         .init();
     // console_subscriber::init();
 
-    let (warp_server, broker_loop) = {
-        use warp::{http::Uri, Filter};
-
-        let (broker, broker_loop) = basic::Broker::create();
-        let broker = warp::any().map(move || broker.clone());
-
-        let channel = warp::path("ws").and(warp::ws()).and(broker).map(
-            |ws: warp::ws::Ws, broker: basic::Broker| {
-                ws.on_upgrade(move |socket| routes::warp::peer_connected(socket, broker))
-            },
-        );
-
-        let redirect_to_app = warp::any().map(|| warp::redirect(Uri::from_static("/app/")));
-        let test =
-            warp::path("test").map(|| warp::reply::html(include_str!("../static/index.html")));
-        let app = warp::path("app").and(warp::fs::dir("./app/dist/"));
-
-        let routes = test.or(app).or(channel).or(redirect_to_app);
-
-        let listen_on: std::net::SocketAddr =
-            format!("{}:{}", config.server.host, config.server.port)
-                .parse()
-                .unwrap();
-
-        let warp_server = warp::serve(routes)
-            .tls()
-            .cert_path("testcerts/cert.pem")
-            .key_path("testcerts/key.pem")
-            .run(listen_on);
-        tracing::info!("warp listening on https://{}", listen_on);
-        (warp_server, broker_loop)
-    };
-
-    let axum_server = async {
-        use axum::{response::Redirect, routing::get, Router};
-        use axum_server::tls_rustls::RustlsConfig;
-        let app = Router::new()
-            .route("/ws", axum::routing::get(routes::axum::peer_connected))
-            .nest_service("/app", ServeDir::new("./app/dist"))
-            .route("/", get(|| async { Redirect::permanent("/app") }));
-
-        let tls_config = RustlsConfig::from_pem_file("testcerts/cert.pem", "testcerts/key.pem")
-            .await
-            .unwrap();
-
-        let listen_on: std::net::SocketAddr =
-            format!("{}:{}", config.server.host, config.server.port + 1)
-                .parse()
-                .unwrap();
-        tracing::info!("axum listening on https://{}", listen_on);
-        axum_server::tls_rustls::bind_rustls(listen_on, tls_config)
-            .serve(app.into_make_service())
-            .await
-            .unwrap()
-    };
-
-    _ = tokio::join! {
-        broker_loop,
-        warp_server,
-        axum_server,
-    };
+    if let Err(error) = server::serve(&config).await {
+        tracing::error!("server failed: {error}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,317 @@
+//! Counters for `/metrics`, in the Prometheus text format.
+//!
+//! Whatever the broker holds (peers, pairs, rooms) is asked for on every scrape,
+//! see [`crate::broker::SignalingBroker::stats`], everything else is counted as it happens.
+
+use std::{
+    fmt::{self, Write as _},
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{broker::BrokerStats, ConnectError};
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// upper bounds of the session duration buckets, in seconds
+const SESSION_BUCKETS: [u64; 8] = [1, 10, 60, 300, 900, 3600, 14400, 86400];
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Durations, bucketed by [`SESSION_BUCKETS`].
+pub struct Histogram {
+    buckets: [Counter; SESSION_BUCKETS.len()],
+    count: Counter,
+    sum_millis: Counter,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { Counter::new() }; SESSION_BUCKETS.len()],
+            count: Counter::new(),
+            sum_millis: Counter::new(),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter().zip(SESSION_BUCKETS) {
+            if secs <= le as f64 {
+                bucket.inc();
+            }
+        }
+        self.count.inc();
+        self.sum_millis.add(duration.as_millis() as u64);
+    }
+}
+
+/// Frames a peer passed on to its correspondent or room, by kind.
+pub struct Forwarded {
+    text: Counter,
+    text_bytes: Counter,
+    binary: Counter,
+    binary_bytes: Counter,
+}
+
+impl Forwarded {
+    const fn new() -> Self {
+        Self {
+            text: Counter::new(),
+            text_bytes: Counter::new(),
+            binary: Counter::new(),
+            binary_bytes: Counter::new(),
+        }
+    }
+
+    pub fn text(&self, len: usize) {
+        self.text.inc();
+        self.text_bytes.add(len as u64);
+    }
+
+    pub fn binary(&self, len: usize) {
+        self.binary.inc();
+        self.binary_bytes.add(len as u64);
+    }
+}
+
+/// Connect attempts, by outcome.
+pub struct Connects {
+    ok: Counter,
+    self_connect: Counter,
+    not_found: Counter,
+    not_running: Counter,
+    already_paired: Counter,
+    not_registered: Counter,
+    delivery_failed: Counter,
+    rejected: Counter,
+    timed_out: Counter,
+}
+
+impl Connects {
+    const fn new() -> Self {
+        Self {
+            ok: Counter::new(),
+            self_connect: Counter::new(),
+            not_found: Counter::new(),
+            not_running: Counter::new(),
+            already_paired: Counter::new(),
+            not_registered: Counter::new(),
+            delivery_failed: Counter::new(),
+            rejected: Counter::new(),
+            timed_out: Counter::new(),
+        }
+    }
+
+    pub fn record<T>(&self, result: &Result<T, ConnectError>) {
+        let counter = match result {
+            Ok(_) => &self.ok,
+            Err(ConnectError::SelfConnect) => &self.self_connect,
+            Err(ConnectError::NotFound) => &self.not_found,
+            Err(ConnectError::NotRunning) => &self.not_running,
+            Err(ConnectError::AlreadyPaired) => &self.already_paired,
+            Err(ConnectError::NotRegistered) => &self.not_registered,
+            Err(ConnectError::DeliveryFailed) => &self.delivery_failed,
+            Err(ConnectError::Rejected) => &self.rejected,
+            Err(ConnectError::TimedOut) => &self.timed_out,
+            // answering a request that isn't there is no attempt
+            Err(ConnectError::NoRequest) => return,
+        };
+        counter.inc();
+    }
+
+    fn by_outcome(&self) -> [(&'static str, &Counter); 9] {
+        [
+            ("ok", &self.ok),
+            ("self_connect", &self.self_connect),
+            ("not_found", &self.not_found),
+            ("not_running", &self.not_running),
+            ("already_paired", &self.already_paired),
+            ("not_registered", &self.not_registered),
+            ("delivery_failed", &self.delivery_failed),
+            ("rejected", &self.rejected),
+            ("timed_out", &self.timed_out),
+        ]
+    }
+}
+
+/// Requests refused by the rate limits, and bans handed out.
+pub struct RateLimits {
+    pub upgrades: Counter,
+    pub connects: Counter,
+    pub bans: Counter,
+}
+
+impl RateLimits {
+    const fn new() -> Self {
+        Self {
+            upgrades: Counter::new(),
+            connects: Counter::new(),
+            bans: Counter::new(),
+        }
+    }
+}
+
+pub struct Metrics {
+    /// open websockets
+    pub websockets: Gauge,
+    /// how long websockets stayed open
+    pub sessions: Histogram,
+    pub connects: Connects,
+    pub forwarded: Forwarded,
+    pub rate_limits: RateLimits,
+    /// dead peers the broker cleaned out
+    pub gc_removed: Counter,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            websockets: Gauge::new(),
+            sessions: Histogram::new(),
+            connects: Connects::new(),
+            forwarded: Forwarded::new(),
+            rate_limits: RateLimits::new(),
+            gc_removed: Counter::new(),
+        }
+    }
+
+    /// Everything in the text exposition format, broker gauges only if the broker answered.
+    pub fn render(&self, stats: Option<BrokerStats>) -> Result<String, fmt::Error> {
+        let mut out = Exposition::default();
+        out.header("websockets", "gauge", "open websockets")?;
+        out.sample("websockets", "", self.websockets.get())?;
+
+        if let Some(stats) = stats {
+            out.header(
+                "peers_unpaired",
+                "gauge",
+                "peers waiting to be connected to",
+            )?;
+            out.sample("peers_unpaired", "", stats.unpaired)?;
+            out.header("pairs", "gauge", "pairs of connected peers")?;
+            out.sample("pairs", "", stats.pairs)?;
+            out.header("room_members", "gauge", "peers in a room")?;
+            out.sample("room_members", "", stats.room_members)?;
+        }
+
+        out.header("connects_total", "counter", "connect attempts by outcome")?;
+        for (outcome, counter) in self.connects.by_outcome() {
+            let labels = format!("{{outcome=\"{outcome}\"}}");
+            out.sample("connects_total", &labels, counter.get())?;
+        }
+
+        let forwarded = &self.forwarded;
+        let (text, binary) = ("{kind=\"text\"}", "{kind=\"binary\"}");
+        out.header(
+            "forwarded_messages_total",
+            "counter",
+            "frames clients sent each other",
+        )?;
+        out.sample("forwarded_messages_total", text, forwarded.text.get())?;
+        out.sample("forwarded_messages_total", binary, forwarded.binary.get())?;
+        out.header(
+            "forwarded_bytes_total",
+            "counter",
+            "bytes clients sent each other",
+        )?;
+        out.sample("forwarded_bytes_total", text, forwarded.text_bytes.get())?;
+        out.sample(
+            "forwarded_bytes_total",
+            binary,
+            forwarded.binary_bytes.get(),
+        )?;
+
+        let limits = &self.rate_limits;
+        out.header(
+            "rate_limited_total",
+            "counter",
+            "requests refused by the rate limits",
+        )?;
+        out.sample(
+            "rate_limited_total",
+            "{what=\"upgrade\"}",
+            limits.upgrades.get(),
+        )?;
+        out.sample(
+            "rate_limited_total",
+            "{what=\"connect\"}",
+            limits.connects.get(),
+        )?;
+        out.header("bans_total", "counter", "client addresses banned")?;
+        out.sample("bans_total", "", limits.bans.get())?;
+
+        out.header(
+            "gc_removed_total",
+            "counter",
+            "dead peers cleaned out by the broker",
+        )?;
+        out.sample("gc_removed_total", "", self.gc_removed.get())?;
+
+        let sessions = &self.sessions;
+        let name = "session_duration_seconds";
+        out.header(name, "histogram", "how long websockets stayed open")?;
+        for (bucket, le) in sessions.buckets.iter().zip(SESSION_BUCKETS) {
+            let labels = format!("_bucket{{le=\"{le}\"}}");
+            out.sample(name, &labels, bucket.get())?;
+        }
+        out.sample(name, "_bucket{le=\"+Inf\"}", sessions.count.get())?;
+        out.sample(name, "_sum", sessions.sum_millis.get() as f64 / 1000.0)?;
+        out.sample(name, "_count", sessions.count.get())?;
+
+        Ok(out.0)
+    }
+}
+
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) -> fmt::Result {
+        writeln!(self.0, "# HELP cast_me_{name} {help}")?;
+        writeln!(self.0, "# TYPE cast_me_{name} {kind}")
+    }
+
+    /// `suffix` holds the labels, and for histograms the `_bucket`, `_sum` or `_count` before them
+    fn sample(&mut self, name: &str, suffix: &str, value: impl fmt::Display) -> fmt::Result {
+        writeln!(self.0, "cast_me_{name}{suffix} {value}")
+    }
+}
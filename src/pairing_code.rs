@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use std::{convert::TryFrom, fmt};
+
+use crate::PeerId;
+
+const DIGITS: usize = 6;
+
+/// Short lived stand-in for a [`PeerId`], easy to read out and good for one connect request.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PairingCode(String);
+
+impl Default for PairingCode {
+    fn default() -> PairingCode {
+        let code = Uuid::new_v4().as_u128() % 10u128.pow(DIGITS as u32);
+        PairingCode(format!("{code:0DIGITS$}"))
+    }
+}
+
+impl TryFrom<String> for PairingCode {
+    type Error = String;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("a pairing code has {DIGITS} digits"));
+        }
+        Ok(PairingCode(code))
+    }
+}
+
+impl fmt::Display for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Whom a [`crate::WsProtocol::Connect`] is for, `{"code": "042917"}` or `{"id": "<id>"}`.
+///
+/// A bare string is an id, as sent by clients that predate pairing codes. Pin ids have as many
+/// digits as codes, so a code has to say so.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", from = "Addressed")]
+pub enum ConnectTo {
+    Code(PairingCode),
+    Id(PeerId),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Addressed {
+    Bare(PeerId),
+    Tagged(Tagged),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Tagged {
+    Code(PairingCode),
+    Id(PeerId),
+}
+
+impl From<Addressed> for ConnectTo {
+    fn from(addressed: Addressed) -> Self {
+        match addressed {
+            Addressed::Bare(id) | Addressed::Tagged(Tagged::Id(id)) => ConnectTo::Id(id),
+            Addressed::Tagged(Tagged::Code(code)) => ConnectTo::Code(code),
+        }
+    }
+}
+
+impl From<PeerId> for ConnectTo {
+    fn from(id: PeerId) -> Self {
+        ConnectTo::Id(id)
+    }
+}
+
+impl fmt::Display for ConnectTo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectTo::Code(code) => write!(f, "code {code}"),
+            ConnectTo::Id(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+impl fmt::Debug for ConnectTo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn connect_to(json: serde_json::Value) -> Result<ConnectTo, String> {
+        serde_json::from_value(json).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn codes_are_tagged() {
+        let code = PairingCode(String::from("042917"));
+        let id: PeerId = serde_json::from_value(json!("042917")).unwrap();
+        assert_eq!(
+            connect_to(json!({ "code": "042917" })),
+            Ok(ConnectTo::Code(code.clone()))
+        );
+        assert_eq!(
+            connect_to(json!({ "id": "042917" })),
+            Ok(ConnectTo::Id(id.clone()))
+        );
+        // a pin id, not a code
+        assert_eq!(connect_to(json!("042917")), Ok(ConnectTo::Id(id.clone())));
+
+        assert_eq!(
+            serde_json::to_value(ConnectTo::Code(code)).unwrap(),
+            json!({ "code": "042917" })
+        );
+        assert_eq!(
+            serde_json::to_value(ConnectTo::Id(id)).unwrap(),
+            json!({ "id": "042917" })
+        );
+    }
+
+    #[test]
+    fn codes_have_six_digits() {
+        assert!(connect_to(json!({ "code": "42917" })).is_err());
+        assert!(connect_to(json!({ "code": "0429170" })).is_err());
+        assert!(connect_to(json!({ "code": "04291x" })).is_err());
+        assert!(connect_to(json!({ "token": "042917" })).is_err());
+        assert!(PairingCode::try_from(String::from("1234567")).is_err());
+
+        for _ in 0..100 {
+            let code = PairingCode::default();
+            assert_eq!(PairingCode::try_from(code.to_string()), Ok(code));
+        }
+    }
+}
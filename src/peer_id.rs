@@ -3,12 +3,15 @@ use uuid::Uuid;
 
 use std::fmt;
 
+use crate::{PeerIdConfig, PeerIdStyle};
+
 #[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PeerId(String);
 
-impl Default for PeerId {
-    fn default() -> PeerId {
-        PeerId(human_hash::humanize(&Uuid::new_v4(), 2))
+impl PeerId {
+    /// What a peer goes by until the broker admits it.
+    pub fn unassigned() -> PeerId {
+        PeerId(String::new())
     }
 }
 
@@ -29,3 +32,227 @@ impl fmt::Debug for PeerId {
         write!(f, "{}", self.0)
     }
 }
+
+/// how often an id is drawn before giving up, the ids are running out by then
+const ATTEMPTS: usize = 32;
+
+/// crockford's alphabet, without the letters that are easily mistaken for digits
+const BASE32: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// The bits of a v4 uuid below its variant are all random.
+const RANDOM_BITS: u32 = 62;
+
+/// Makes up new peer ids, see [`PeerIdConfig`].
+///
+/// Ids are random, so they may collide, the broker draws again until it finds one
+/// that no live peer has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdGenerator {
+    /// this many words, e.g. `alpha-bravo`
+    Words(usize),
+    /// this many decimal digits, e.g. `042917`
+    Pin(usize),
+    /// this many base32 characters, e.g. `7hq2kx0m`
+    Base32(usize),
+    /// a hyphenated uuid
+    Uuid,
+}
+
+impl Default for IdGenerator {
+    fn default() -> Self {
+        IdGenerator::Words(2)
+    }
+}
+
+impl IdGenerator {
+    const MAX_WORDS: usize = 8;
+    const MAX_DIGITS: usize = 18;
+    const MAX_BASE32: usize = (RANDOM_BITS / 5) as usize;
+
+    pub fn new(config: &PeerIdConfig) -> Self {
+        match config.style {
+            PeerIdStyle::Words => IdGenerator::Words(config.length.unwrap_or(2)),
+            PeerIdStyle::Pin => IdGenerator::Pin(config.length.unwrap_or(6)),
+            PeerIdStyle::Base32 => IdGenerator::Base32(config.length.unwrap_or(8)),
+            PeerIdStyle::Uuid => IdGenerator::Uuid,
+        }
+    }
+
+    /// Whether the generator can make up ids at all, with a message for the config otherwise.
+    pub fn check(&self) -> Result<(), String> {
+        let (length, max) = match *self {
+            IdGenerator::Words(words) => (words, Self::MAX_WORDS),
+            IdGenerator::Pin(digits) => (digits, Self::MAX_DIGITS),
+            IdGenerator::Base32(chars) => (chars, Self::MAX_BASE32),
+            IdGenerator::Uuid => return Ok(()),
+        };
+        if !(1..=max).contains(&length) {
+            return Err(format!("must be between 1 and {max}"));
+        }
+        Ok(())
+    }
+
+    pub fn generate(&self) -> PeerId {
+        let uuid = Uuid::new_v4();
+        let random = uuid.as_u128() as u64 & ((1 << RANDOM_BITS) - 1);
+        let id = match *self {
+            IdGenerator::Words(words) => human_hash::humanize(&uuid, words),
+            IdGenerator::Pin(digits) => {
+                let pin = random % 10u64.pow(digits as u32);
+                format!("{pin:0width$}", width = digits)
+            }
+            IdGenerator::Base32(chars) => (0..chars)
+                .map(|i| char::from(BASE32[(random >> (5 * i)) as usize & 31]))
+                .collect(),
+            IdGenerator::Uuid => uuid.hyphenated().to_string(),
+        };
+        PeerId(id)
+    }
+
+    /// An id that `taken` says nobody goes by, `None` if the ids are running out.
+    pub fn generate_unique(&self, taken: impl Fn(&PeerId) -> bool) -> Option<PeerId> {
+        std::iter::repeat_with(|| self.generate())
+            .take(ATTEMPTS)
+            .find(|id| {
+                let taken = taken(id);
+                if taken {
+                    tracing::debug!("{id} is taken, drawing another");
+                }
+                !taken
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// enough draws to see every character of a style
+    const DRAWS: usize = 200;
+
+    fn draws(generator: IdGenerator) -> impl Iterator<Item = String> {
+        (0..DRAWS).map(move |_| generator.generate().to_string())
+    }
+
+    #[test]
+    fn new() {
+        let config = |style, length| PeerIdConfig { style, length };
+        assert_eq!(
+            IdGenerator::new(&config(PeerIdStyle::Words, None)),
+            IdGenerator::Words(2)
+        );
+        assert_eq!(
+            IdGenerator::new(&config(PeerIdStyle::Pin, None)),
+            IdGenerator::Pin(6)
+        );
+        assert_eq!(
+            IdGenerator::new(&config(PeerIdStyle::Base32, None)),
+            IdGenerator::Base32(8)
+        );
+        assert_eq!(
+            IdGenerator::new(&config(PeerIdStyle::Pin, Some(9))),
+            IdGenerator::Pin(9)
+        );
+        assert_eq!(
+            IdGenerator::new(&config(PeerIdStyle::Uuid, Some(9))),
+            IdGenerator::Uuid
+        );
+    }
+
+    #[test]
+    fn words() {
+        for length in [1, 2, IdGenerator::MAX_WORDS] {
+            for id in draws(IdGenerator::Words(length)) {
+                let words = id.split('-').collect::<Vec<_>>();
+                assert_eq!(words.len(), length, "{}", id);
+                assert!(
+                    words.iter().all(
+                        |word| !word.is_empty() && word.bytes().all(|b| b.is_ascii_lowercase())
+                    ),
+                    "{}",
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pin() {
+        for length in [1, 6, IdGenerator::MAX_DIGITS] {
+            for id in draws(IdGenerator::Pin(length)) {
+                assert_eq!(id.len(), length, "{}", id);
+                assert!(id.bytes().all(|b| b.is_ascii_digit()), "{}", id);
+            }
+        }
+        // leading zeros are kept
+        assert!(draws(IdGenerator::Pin(2)).any(|id| id.starts_with('0')));
+    }
+
+    #[test]
+    fn base32() {
+        for length in [1, 8, IdGenerator::MAX_BASE32] {
+            for id in draws(IdGenerator::Base32(length)) {
+                assert_eq!(id.len(), length, "{}", id);
+                assert!(id.bytes().all(|b| BASE32.contains(&b)), "{}", id);
+            }
+        }
+        let mut seen = draws(IdGenerator::Base32(IdGenerator::MAX_BASE32))
+            .flat_map(|id| id.into_bytes())
+            .collect::<Vec<_>>();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen, BASE32);
+    }
+
+    #[test]
+    fn uuid() {
+        for id in draws(IdGenerator::Uuid) {
+            assert!(Uuid::parse_str(&id).is_ok(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn check() {
+        let valid = [
+            IdGenerator::Words(1),
+            IdGenerator::Words(8),
+            IdGenerator::Pin(1),
+            IdGenerator::Pin(18),
+            IdGenerator::Base32(1),
+            IdGenerator::Base32(12),
+            IdGenerator::Uuid,
+        ];
+        for generator in valid {
+            assert_eq!(generator.check(), Ok(()), "{:?}", generator);
+        }
+        let invalid = [
+            (IdGenerator::Words(0), "must be between 1 and 8"),
+            (IdGenerator::Words(9), "must be between 1 and 8"),
+            (IdGenerator::Pin(0), "must be between 1 and 18"),
+            (IdGenerator::Pin(19), "must be between 1 and 18"),
+            (IdGenerator::Base32(0), "must be between 1 and 12"),
+            (IdGenerator::Base32(13), "must be between 1 and 12"),
+        ];
+        for (generator, message) in invalid {
+            assert_eq!(
+                generator.check(),
+                Err(String::from(message)),
+                "{:?}",
+                generator
+            );
+        }
+    }
+
+    #[test]
+    fn generate_unique() {
+        let generator = IdGenerator::Pin(1);
+        assert_eq!(generator.generate_unique(|_| true), None);
+
+        // half of them are taken, 32 draws find one of the others
+        let taken = |id: &PeerId| id.0.as_str() < "5";
+        for _ in 0..DRAWS {
+            let id = generator.generate_unique(taken).unwrap();
+            assert!(!taken(&id), "{}", id);
+        }
+    }
+}
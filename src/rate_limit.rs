@@ -0,0 +1,473 @@
+//! Throttles websocket upgrades and connect requests, per client address and per peer.
+//!
+//! Peer ids are short enough to be guessed, so an address that keeps asking for peers
+//! that don't exist is banned for a while.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    metrics::METRICS,
+    ws_protocol::{ErrorCode, WsProtocol},
+    PeerId, RateLimitConfig,
+};
+
+/// how often buckets, misses and bans that no longer matter are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    /// out of tokens until then
+    Throttled(Duration),
+    /// the address is banned for this long
+    Banned(Duration),
+}
+
+impl Limited {
+    /// Whole seconds, for `Retry-After`.
+    pub fn retry_after_secs(&self) -> u64 {
+        let (Limited::Throttled(wait) | Limited::Banned(wait)) = self;
+        wait.as_secs_f64().ceil() as u64
+    }
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.retry_after_secs();
+        match self {
+            Limited::Throttled(_) => {
+                let message = ErrorCode::RateLimited.message();
+                write!(f, "{message}, try again in {secs}s")
+            }
+            Limited::Banned(_) => write!(f, "banned for {secs}s"),
+        }
+    }
+}
+
+impl From<Limited> for WsProtocol {
+    fn from(limited: Limited) -> Self {
+        WsProtocol::Error {
+            code: ErrorCode::RateLimited,
+            message: limited.to_string(),
+        }
+    }
+}
+
+/// `burst` requests at once, refilled with `per_minute`.
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_minute: u32,
+    burst: u32,
+}
+
+impl RateLimitConfig {
+    fn upgrades(&self) -> Rate {
+        Rate {
+            per_minute: self.upgrades_per_minute,
+            burst: self.upgrade_burst,
+        }
+    }
+
+    fn connects_per_ip(&self) -> Rate {
+        Rate {
+            per_minute: self.connects_per_minute,
+            burst: self.connect_burst,
+        }
+    }
+
+    fn connects_per_peer(&self) -> Rate {
+        Rate {
+            per_minute: self.peer_connects_per_minute,
+            burst: self.peer_connect_burst,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let refill = elapsed * f64::from(rate.per_minute) / 60.0;
+        self.tokens = (self.tokens + refill).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    /// Takes a token, or says how long until there is one.
+    fn take(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - self.tokens;
+        Err(Duration::from_secs_f64(
+            missing * 60.0 / f64::from(rate.per_minute),
+        ))
+    }
+
+    /// A full bucket is no different from a new one.
+    fn is_full(&mut self, rate: Rate, now: Instant) -> bool {
+        self.refill(rate, now);
+        self.tokens >= f64::from(rate.burst)
+    }
+}
+
+/// Misses within the current window.
+#[derive(Debug)]
+struct Misses {
+    count: u32,
+    since: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    upgrades: HashMap<IpAddr, TokenBucket>,
+    connects_by_ip: HashMap<IpAddr, TokenBucket>,
+    connects_by_peer: HashMap<PeerId, TokenBucket>,
+    misses: HashMap<IpAddr, Misses>,
+    /// until when
+    bans: HashMap<IpAddr, Instant>,
+}
+
+/// Shared by every websocket, see [`RateLimitConfig`].
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+/// ipv6 clients can pick any address of their /64, so they are told apart by it.
+fn client(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => {
+                let prefix = u128::from(ip) & !u128::from(u64::MAX);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+        },
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            buckets: Arc::default(),
+        }
+    }
+
+    fn buckets(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        // the buckets stay consistent even if a holder panicked
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn banned(buckets: &Buckets, ip: &IpAddr, now: Instant) -> Result<(), Limited> {
+        match buckets.bans.get(ip) {
+            Some(until) if *until > now => Err(Limited::Banned(*until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    /// A new websocket from `ip`.
+    pub fn upgrade(&self, ip: IpAddr) -> Result<(), Limited> {
+        self.upgrade_at(ip, Instant::now())
+    }
+
+    /// A connect request of `peer`, whose websocket came from `ip`.
+    pub fn connect(&self, ip: IpAddr, peer: &PeerId) -> Result<(), Limited> {
+        self.connect_at(ip, peer, Instant::now())
+    }
+
+    /// `ip` asked for a peer that doesn't exist, too many of those and it is banned.
+    pub fn missed(&self, ip: IpAddr) {
+        self.missed_at(ip, Instant::now());
+    }
+
+    fn upgrade_at(&self, ip: IpAddr, now: Instant) -> Result<(), Limited> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let ip = client(ip);
+        let mut buckets = self.buckets();
+        let rate = self.config.upgrades();
+        let limited = Self::banned(&buckets, &ip, now).and_then(|()| {
+            buckets
+                .upgrades
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::full(rate, now))
+                .take(rate, now)
+                .map_err(Limited::Throttled)
+        });
+        if let Err(limited) = limited {
+            tracing::warn!(%ip, "refusing websocket: {limited}");
+            METRICS.rate_limits.upgrades.inc();
+        }
+        limited
+    }
+
+    fn connect_at(&self, ip: IpAddr, peer: &PeerId, now: Instant) -> Result<(), Limited> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let ip = client(ip);
+        let mut buckets = self.buckets();
+        let (by_ip, by_peer) = (
+            self.config.connects_per_ip(),
+            self.config.connects_per_peer(),
+        );
+        let limited = Self::banned(&buckets, &ip, now).and_then(|()| {
+            // both buckets have to have a token, neither is taken from otherwise
+            let mut ip_bucket = *buckets
+                .connects_by_ip
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::full(by_ip, now));
+            let mut peer_bucket = *buckets
+                .connects_by_peer
+                .entry(peer.clone())
+                .or_insert_with(|| TokenBucket::full(by_peer, now));
+            ip_bucket
+                .take(by_ip, now)
+                .and_then(|()| peer_bucket.take(by_peer, now))
+                .map_err(Limited::Throttled)?;
+            buckets.connects_by_ip.insert(ip, ip_bucket);
+            buckets.connects_by_peer.insert(peer.clone(), peer_bucket);
+            Ok(())
+        });
+        if let Err(limited) = limited {
+            tracing::warn!(%ip, %peer, "refusing connect request: {limited}");
+            METRICS.rate_limits.connects.inc();
+        }
+        limited
+    }
+
+    fn missed_at(&self, ip: IpAddr, now: Instant) {
+        let config = &self.config;
+        if !config.enabled || config.ban_after_misses == 0 {
+            return;
+        }
+        let ip = client(ip);
+        let window = Duration::from_secs(config.miss_window_secs);
+        let mut buckets = self.buckets();
+        let misses = buckets.misses.entry(ip).or_insert(Misses {
+            count: 0,
+            since: now,
+        });
+        if now.duration_since(misses.since) > window {
+            misses.count = 0;
+            misses.since = now;
+        }
+        misses.count += 1;
+        if misses.count < config.ban_after_misses {
+            return;
+        }
+
+        let ban = Duration::from_secs(config.ban_secs);
+        tracing::warn!(%ip, "banned for {ban:?} after {} misses", misses.count);
+        METRICS.rate_limits.bans.inc();
+        buckets.misses.remove(&ip);
+        buckets.bans.insert(ip, now + ban);
+    }
+
+    /// Forget full buckets, old misses and bans that ran out.
+    fn prune_at(&self, now: Instant) {
+        let config = &self.config;
+        let window = Duration::from_secs(config.miss_window_secs);
+        let mut buckets = self.buckets();
+        buckets
+            .upgrades
+            .retain(|_, bucket| !bucket.is_full(config.upgrades(), now));
+        buckets
+            .connects_by_ip
+            .retain(|_, bucket| !bucket.is_full(config.connects_per_ip(), now));
+        buckets
+            .connects_by_peer
+            .retain(|_, bucket| !bucket.is_full(config.connects_per_peer(), now));
+        buckets
+            .misses
+            .retain(|_, misses| now.duration_since(misses.since) <= window);
+        buckets.bans.retain(|_, until| *until > now);
+    }
+
+    /// Prunes regularly, for as long as the server runs.
+    pub async fn prune_regularly(self) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut ticks = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            ticks.tick().await;
+            self.prune_at(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn peer(id: &str) -> PeerId {
+        serde_json::from_value(json!(id)).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// A limiter and a clock that starts when it was created.
+    fn limiter(config: RateLimitConfig) -> (RateLimiter, impl Fn(u64) -> Instant) {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(&config);
+        (limiter, move |secs| start + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn bucket_refills_up_to_its_burst() {
+        let rate = Rate {
+            per_minute: 60,
+            burst: 2,
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut bucket = TokenBucket::full(rate, at(0));
+        assert_eq!(bucket.take(rate, at(0)), Ok(()));
+        assert_eq!(bucket.take(rate, at(0)), Ok(()));
+        assert_eq!(bucket.take(rate, at(0)), Err(Duration::from_secs(1)));
+        assert_eq!(bucket.take(rate, at(1)), Ok(()));
+
+        bucket.refill(rate, at(100));
+        assert_eq!(bucket.tokens, 2.0);
+        assert!(bucket.is_full(rate, at(100)));
+    }
+
+    #[test]
+    fn connect_takes_from_both_buckets_or_neither() {
+        let (limiter, at) = limiter(RateLimitConfig {
+            connects_per_minute: 30,
+            connect_burst: 2,
+            peer_connects_per_minute: 30,
+            peer_connect_burst: 1,
+            ..Default::default()
+        });
+        let (client, alpha, bravo, charlie) = (ip("192.0.2.1"), peer("a"), peer("b"), peer("c"));
+        assert_eq!(limiter.connect_at(client, &alpha, at(0)), Ok(()));
+        // alpha is out of tokens, the address keeps its second one
+        assert_eq!(
+            limiter.connect_at(client, &alpha, at(0)),
+            Err(Limited::Throttled(Duration::from_secs(2)))
+        );
+        assert_eq!(limiter.buckets().connects_by_ip[&client].tokens, 1.0);
+
+        assert_eq!(limiter.connect_at(client, &bravo, at(0)), Ok(()));
+        // the address is out of tokens, charlie keeps its one
+        assert_eq!(
+            limiter.connect_at(client, &charlie, at(0)),
+            Err(Limited::Throttled(Duration::from_secs(2)))
+        );
+        assert_eq!(limiter.buckets().connects_by_peer[&charlie].tokens, 1.0);
+    }
+
+    #[test]
+    fn misses_within_the_window_ban() {
+        let (limiter, at) = limiter(RateLimitConfig {
+            ban_after_misses: 3,
+            miss_window_secs: 60,
+            ban_secs: 300,
+            ..Default::default()
+        });
+        let client = ip("192.0.2.1");
+        limiter.missed_at(client, at(0));
+        limiter.missed_at(client, at(10));
+        // the window has passed, counting starts over
+        limiter.missed_at(client, at(80));
+        limiter.missed_at(client, at(90));
+        assert_eq!(limiter.upgrade_at(client, at(90)), Ok(()));
+
+        limiter.missed_at(client, at(100));
+        let banned = Err(Limited::Banned(Duration::from_secs(200)));
+        assert_eq!(limiter.upgrade_at(client, at(200)), banned);
+        assert_eq!(limiter.connect_at(client, &peer("a"), at(200)), banned);
+        assert_eq!(limiter.upgrade_at(client, at(400)), Ok(()));
+    }
+
+    #[test]
+    fn ipv6_clients_are_told_apart_by_their_64() {
+        assert_eq!(client(ip("2001:db8::1")), ip("2001:db8::"));
+        assert_eq!(client(ip("2001:db8::ffff:1")), ip("2001:db8::"));
+        assert_eq!(client(ip("2001:db8:0:1::1")), ip("2001:db8:0:1::"));
+        assert_eq!(client(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(client(ip("192.0.2.1")), ip("192.0.2.1"));
+
+        let (limiter, at) = limiter(RateLimitConfig {
+            upgrade_burst: 1,
+            ..Default::default()
+        });
+        assert_eq!(limiter.upgrade_at(ip("2001:db8::1"), at(0)), Ok(()));
+        assert!(limiter.upgrade_at(ip("2001:db8::2"), at(0)).is_err());
+        assert_eq!(limiter.upgrade_at(ip("2001:db8:0:1::1"), at(0)), Ok(()));
+    }
+
+    #[test]
+    fn prune_forgets_what_no_longer_matters() {
+        let (limiter, at) = limiter(RateLimitConfig {
+            upgrades_per_minute: 60,
+            upgrade_burst: 10,
+            ban_after_misses: 2,
+            miss_window_secs: 60,
+            ban_secs: 300,
+            ..Default::default()
+        });
+        let (busy, quiet, missing, banned) = (
+            ip("192.0.2.1"),
+            ip("192.0.2.2"),
+            ip("192.0.2.3"),
+            ip("192.0.2.4"),
+        );
+        for _ in 0..10 {
+            limiter.upgrade_at(busy, at(0)).unwrap();
+        }
+        limiter.upgrade_at(quiet, at(0)).unwrap();
+        limiter.missed_at(missing, at(0));
+        limiter.missed_at(banned, at(0));
+        limiter.missed_at(banned, at(0));
+
+        limiter.prune_at(at(5));
+        {
+            let buckets = limiter.buckets();
+            assert!(buckets.upgrades.contains_key(&busy));
+            // refilled, so no different from a new bucket
+            assert!(!buckets.upgrades.contains_key(&quiet));
+            assert!(buckets.misses.contains_key(&missing));
+            assert!(buckets.bans.contains_key(&banned));
+        }
+
+        limiter.prune_at(at(300));
+        let buckets = limiter.buckets();
+        assert!(buckets.upgrades.is_empty());
+        assert!(buckets.misses.is_empty());
+        assert!(buckets.bans.is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+//! Optional TURN relay, built on the webrtc-rs `turn` server.
+//!
+//! Credentials follow the "TURN REST API" scheme: the username is `<expiry>:<peer id>`,
+//! the password is the base64 encoded HMAC-SHA1 of the username, keyed with a shared secret.
+
+use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use ring::hmac;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream, UdpSocket,
+    },
+    sync::{Mutex, Notify},
+};
+use turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::relay_static::RelayAddressGeneratorStatic,
+    server::{
+        config::{ConnConfig, ServerConfig as TurnServerConfig},
+        Server,
+    },
+};
+use webrtc_util::{vnet::net::Net, Conn};
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{clock::unix_time, ws_protocol::IceServer, PeerId, ServerConfig, TurnConfig};
+
+fn password(secret: &str, username: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+    BASE64_STANDARD.encode(hmac::sign(&key, username.as_bytes()))
+}
+
+/// The relay at `urls` with credentials for `id`, valid for `ttl`.
+pub fn ice_server(urls: Vec<String>, secret: &str, ttl: Duration, id: &PeerId) -> IceServer {
+    let expires = unix_time() + ttl.as_secs();
+    let username = format!("{}:{}", expires, id);
+    IceServer {
+        urls,
+        credential: Some(password(secret, &username)),
+        username: Some(username),
+        expires: Some(expires),
+    }
+}
+
+struct RestApiAuth {
+    secret: String,
+}
+
+impl AuthHandler for RestApiAuth {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, turn::Error> {
+        let expires = username
+            .split(':')
+            .next()
+            .and_then(|expires| expires.parse::<u64>().ok())
+            .ok_or_else(|| turn::Error::Other(format!("malformed username {username}")))?;
+        if expires < unix_time() {
+            tracing::debug!("{src_addr} presented expired credentials of {username}");
+            return Err(turn::Error::Other(format!("{username} has expired")));
+        }
+        Ok(generate_auth_key(
+            username,
+            realm,
+            &password(&self.secret, username),
+        ))
+    }
+}
+
+/// TURN over TCP, STUN messages and ChannelData are framed by their length field.
+struct TcpConn {
+    reader: Mutex<OwnedReadHalf>,
+    writer: Mutex<OwnedWriteHalf>,
+    local: SocketAddr,
+    remote: SocketAddr,
+    closed: Notify,
+}
+
+impl TcpConn {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        let local = stream.local_addr()?;
+        let remote = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        Ok(Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            local,
+            remote,
+            closed: Notify::new(),
+        })
+    }
+}
+
+/// Length of the message starting with `header`, and of the padding that follows it.
+fn frame_len(header: [u8; 4]) -> io::Result<(usize, usize)> {
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    match header[0] >> 6 {
+        // stun message, 20 byte header
+        0b00 => Ok((20 + length, 0)),
+        // channel data, padded to 4 bytes over tcp
+        0b01 => Ok((4 + length, (4 - length % 4) % 4)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a turn frame",
+        )),
+    }
+}
+
+#[async_trait]
+impl Conn for TcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc_util::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported).into())
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        Ok(self.recv_from(buf).await?.0)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        let mut reader = self.reader.lock().await;
+        let mut header = [0; 4];
+        reader.read_exact(&mut header).await?;
+        let (len, padding) = frame_len(header)?;
+        if len > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large").into());
+        }
+        buf[..4].copy_from_slice(&header);
+        reader.read_exact(&mut buf[4..len]).await?;
+        reader.read_exact(&mut [0; 3][..padding]).await?;
+        Ok((len, self.remote))
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.send_to(buf, self.remote).await
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> webrtc_util::Result<usize> {
+        if buf.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too short").into());
+        }
+        let (_, padding) = frame_len([buf[0], buf[1], buf[2], buf[3]])?;
+        let mut writer = self.writer.lock().await;
+        writer.write_all(buf).await?;
+        writer.write_all(&[0; 3][..padding]).await?;
+        Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(self.remote)
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.closed.notify_one();
+        self.writer.lock().await.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn server_config(
+    turn: &TurnConfig,
+    relay_ip: IpAddr,
+    conn: Arc<dyn Conn + Send + Sync>,
+) -> TurnServerConfig {
+    TurnServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                relay_address: relay_ip,
+                address: String::from(if relay_ip.is_ipv4() { "0.0.0.0" } else { "::" }),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: turn.realm.clone(),
+        auth_handler: Arc::new(RestApiAuth {
+            secret: turn.secret.clone(),
+        }),
+        channel_bind_timeout: Duration::from_secs(0),
+        alloc_close_notify: None,
+    }
+}
+
+/// Every tcp connection gets a server of its own, allocations are bound to the connection anyway.
+async fn serve_tcp(listener: TcpListener, turn: TurnConfig, relay_ip: IpAddr) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                tracing::debug!("turn failed to accept: {error}");
+                continue;
+            }
+        };
+        let conn = match TcpConn::new(stream) {
+            Ok(conn) => Arc::new(conn),
+            Err(error) => {
+                tracing::debug!("turn failed to set up connection: {error}");
+                continue;
+            }
+        };
+        let config = server_config(&turn, relay_ip, conn.clone());
+
+        tokio::spawn(async move {
+            tracing::debug!("turn over tcp from {}", conn.remote);
+            let server = match Server::new(config).await {
+                Ok(server) => server,
+                Err(error) => {
+                    tracing::warn!("failed to start turn server: {error}");
+                    return;
+                }
+            };
+            conn.closed.notified().await;
+            if let Err(error) = server.close().await {
+                tracing::debug!("failed to close turn server: {error}");
+            }
+        });
+    }
+}
+
+/// Start the relay on `turn.port`, it runs as long as the returned server is kept around.
+pub async fn start(server: &ServerConfig) -> anyhow::Result<Server> {
+    let turn = &server.turn;
+    let listen_on = server.socket_addr(turn.port)?;
+    let relay_ip = turn.relay_ip.unwrap_or_else(|| listen_on.ip());
+
+    let udp = UdpSocket::bind(listen_on).await?;
+    let relay = Server::new(server_config(turn, relay_ip, Arc::new(udp))).await?;
+    tracing::info!("turn listening on udp://{listen_on}, relaying from {relay_ip}");
+
+    if turn.tcp {
+        let listener = TcpListener::bind(listen_on).await?;
+        tracing::info!("turn listening on tcp://{listen_on}");
+        tokio::spawn(serve_tcp(listener, turn.clone(), relay_ip));
+    }
+
+    Ok(relay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "north-facing-secret";
+    const REALM: &str = "webrtc.example";
+
+    fn auth() -> RestApiAuth {
+        RestApiAuth {
+            secret: SECRET.into(),
+        }
+    }
+
+    fn from() -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], 4711))
+    }
+
+    #[test]
+    fn minted_credentials() {
+        let id = serde_json::from_value("alice".into()).unwrap();
+        let before = unix_time();
+        let server = ice_server(
+            vec!["turn:turn.example:3478".into()],
+            SECRET,
+            Duration::from_secs(600),
+            &id,
+        );
+        let expires = server.expires.unwrap();
+        assert!((before + 600..=unix_time() + 600).contains(&expires));
+        let username = server.username.unwrap();
+        assert_eq!(username, format!("{expires}:alice"));
+        let credential = server.credential.unwrap();
+        assert_eq!(credential, password(SECRET, &username));
+
+        let key = auth().auth_handle(&username, REALM, from()).unwrap();
+        assert_eq!(key, generate_auth_key(&username, REALM, &credential));
+    }
+
+    #[test]
+    fn expired() {
+        let username = format!("{}:alice", unix_time() - 1);
+        assert!(auth().auth_handle(&username, REALM, from()).is_err());
+    }
+
+    #[test]
+    fn malformed_username() {
+        assert!(auth().auth_handle("alice", REALM, from()).is_err());
+        assert!(auth().auth_handle("soon:alice", REALM, from()).is_err());
+    }
+
+    #[test]
+    fn other_secret() {
+        let username = format!("{}:alice", unix_time() + 600);
+        let forged = password("guessed-secret", &username);
+        let key = auth().auth_handle(&username, REALM, from()).unwrap();
+        // the integrity of requests signed with it won't check out
+        assert_ne!(key, generate_auth_key(&username, REALM, &forged));
+    }
+}
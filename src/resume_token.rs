@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use std::fmt;
+
+/// Secret that allows a new websocket to take over a dropped session.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ResumeToken(String);
+
+impl Default for ResumeToken {
+    fn default() -> ResumeToken {
+        ResumeToken(Uuid::new_v4().simple().to_string())
+    }
+}
+
+impl fmt::Debug for ResumeToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // don't leak secrets into the logs
+        write!(f, "ResumeToken(***)")
+    }
+}
@@ -1,16 +1,198 @@
-pub mod axum {
-    use axum::{extract::ws::WebSocketUpgrade, response::Response};
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    auth::{AuthError, Authenticator, Identity},
+    broker::BrokerStats,
+    forwarded,
+    keepalive::Timeouts,
+    metrics::METRICS,
+    rate_limit::{Limited, RateLimiter},
+    shutdown::Sessions,
+    ServerConfig,
+};
+
+/// Shared by the websocket routes of both backends.
+#[derive(Debug, Clone)]
+pub struct Upgrades {
+    pub server: Arc<ServerConfig>,
+    pub timeouts: Timeouts,
+    /// refuses new websockets while shutting down
+    pub sessions: Sessions,
+    /// `None` if anybody may connect
+    pub auth: Option<Arc<dyn Authenticator>>,
+    /// throttles upgrades here, connect requests in the peers
+    pub limiter: RateLimiter,
+}
+
+impl Upgrades {
+    /// Whom the upgrade request comes from, `None` if anybody may connect.
+    fn authenticate(&self, headers: &HeaderMap, uri: &Uri) -> Result<Option<Identity>, AuthError> {
+        self.auth
+            .as_ref()
+            .map(|auth| auth.authenticate(headers, uri))
+            .transpose()
+    }
+
+    /// Where the upgrade request comes from, proxies taken into account.
+    fn client_addr(&self, headers: &HeaderMap, remote_addr: SocketAddr) -> SocketAddr {
+        forwarded::client_addr(&self.server.trusted_proxies, headers, remote_addr)
+    }
+}
+
+/// The token is missing or invalid, sent before upgrading.
+fn unauthorized(error: AuthError) -> Response {
+    tracing::info!("refusing websocket: {error}");
+    let challenge = [(header::WWW_AUTHENTICATE, "Bearer")];
+    (StatusCode::UNAUTHORIZED, challenge, error.to_string()).into_response()
+}
+
+/// Too many upgrades from the same address, or it is banned.
+fn too_many_requests(limited: Limited) -> Response {
+    let retry_after = [(header::RETRY_AFTER, limited.retry_after_secs())];
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        retry_after,
+        limited.to_string(),
+    )
+        .into_response()
+}
+
+/// how long the broker gets to answer a health check
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 200 if the broker answers in time, 503 otherwise.
+async fn probe(
+    health_check: impl Future<Output = anyhow::Result<()>>,
+) -> (StatusCode, &'static str) {
+    match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, health_check).await {
+        Ok(Ok(())) => (StatusCode::OK, "ok"),
+        Ok(Err(error)) => {
+            tracing::error!("health check failed: {error}");
+            (StatusCode::SERVICE_UNAVAILABLE, "broker is gone")
+        }
+        Err(_) => {
+            tracing::error!("broker did not answer within {HEALTH_CHECK_TIMEOUT:?}");
+            (StatusCode::SERVICE_UNAVAILABLE, "broker is not responding")
+        }
+    }
+}
+
+/// Ready as long as the broker is alive and the server isn't shutting down.
+async fn ready(
+    upgrades: &Upgrades,
+    health_check: impl Future<Output = anyhow::Result<()>>,
+) -> (StatusCode, &'static str) {
+    if upgrades.sessions.is_closing() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+    }
+    probe(health_check).await
+}
+
+/// Everything counted so far, along with what the broker holds right now.
+async fn metrics(stats: impl Future<Output = anyhow::Result<BrokerStats>>) -> Response {
+    let stats = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, stats).await {
+        Ok(Ok(stats)) => Some(stats),
+        Ok(Err(error)) => {
+            tracing::warn!("failed to collect broker stats: {error}");
+            None
+        }
+        Err(_) => {
+            tracing::warn!("broker did not answer within {HEALTH_CHECK_TIMEOUT:?}");
+            None
+        }
+    };
+    match METRICS.render(stats) {
+        Ok(metrics) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics,
+        )
+            .into_response(),
+        Err(error) => {
+            tracing::error!("failed to render metrics: {error}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// The host the client used to reach us, without the port.
+fn requested_host(headers: &HeaderMap) -> Option<&str> {
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    if let Some(end) = host.find(']') {
+        // [::1]:3030
+        return Some(&host[..=end]);
+    }
+    Some(host.split(':').next().unwrap_or(host))
+}
+
+pub mod actors {
+    use axum::{
+        extract::{ws::WebSocketUpgrade, ConnectInfo, State},
+        http::{HeaderMap, StatusCode, Uri},
+        response::{IntoResponse, Response},
+    };
     use futures::StreamExt;
+    use hannibal::Service as _;
+
+    use crate::{
+        actors::{Broker, Peer},
+        broker::{PeerInfo, SignalingBroker as _},
+        IceServers,
+    };
+
+    use std::net::SocketAddr;
+
+    use super::Upgrades;
+
+    async fn health_check() -> anyhow::Result<()> {
+        Broker::from_registry().await.health_check().await
+    }
+
+    pub async fn healthz() -> impl IntoResponse {
+        super::probe(health_check()).await
+    }
 
-    use crate::actors::Peer;
+    pub async fn readyz(State(upgrades): State<Upgrades>) -> impl IntoResponse {
+        super::ready(&upgrades, health_check()).await
+    }
+
+    pub async fn metrics() -> Response {
+        super::metrics(async { Broker::from_registry().await.stats().await }).await
+    }
 
-    pub async fn peer_connected(ws: WebSocketUpgrade) -> Response {
-        ws.on_upgrade(|socket| async move {
+    pub async fn peer_connected(
+        ws: WebSocketUpgrade,
+        headers: HeaderMap,
+        uri: Uri,
+        ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+        State(upgrades): State<Upgrades>,
+    ) -> Response {
+        let remote_addr = upgrades.client_addr(&headers, remote_addr);
+        if let Err(limited) = upgrades.limiter.upgrade(remote_addr.ip()) {
+            return super::too_many_requests(limited);
+        }
+        let user = match upgrades.authenticate(&headers, &uri) {
+            Ok(user) => user,
+            Err(error) => return super::unauthorized(error),
+        };
+        let Some(session) = upgrades.sessions.open() else {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        };
+        let ice = IceServers::new(&upgrades.server, super::requested_host(&headers));
+        let timeouts = upgrades.timeouts;
+        let limiter = upgrades.limiter.clone();
+        let info = PeerInfo::new(remote_addr, user);
+        ws.on_upgrade(move |socket| async move {
             let (sender, messages) = socket.split();
-            if let Err(error) = hannibal::build(Peer::new(sender))
-                .on_stream(messages)
-                .spawn()
-                .await
+            if let Err(error) =
+                hannibal::build(Peer::new(sender, ice, timeouts, session, info, limiter))
+                    .on_stream(messages)
+                    .spawn()
+                    .await
             {
                 tracing::warn!("websocket peer failed {error}")
             }
@@ -18,17 +200,70 @@ pub mod axum {
         })
     }
 }
-pub mod warp {
-    use warp::filters::ws::WebSocket;
+pub mod basic {
+    use axum::{
+        extract::{ws::WebSocketUpgrade, ConnectInfo, State},
+        http::{HeaderMap, StatusCode, Uri},
+        response::{IntoResponse, Response},
+    };
+    use futures::StreamExt;
+
+    use crate::{
+        basic::{Broker, Peer},
+        broker::{PeerInfo, SignalingBroker as _},
+        IceServers,
+    };
+
+    use std::net::SocketAddr;
+
+    use super::Upgrades;
+
+    pub async fn healthz(State((broker, _)): State<(Broker, Upgrades)>) -> impl IntoResponse {
+        super::probe(broker.health_check()).await
+    }
 
-    use crate::basic::{Broker, Peer};
+    pub async fn readyz(State((broker, upgrades)): State<(Broker, Upgrades)>) -> impl IntoResponse {
+        super::ready(&upgrades, broker.health_check()).await
+    }
+
+    pub async fn metrics(State((broker, _)): State<(Broker, Upgrades)>) -> Response {
+        super::metrics(broker.stats()).await
+    }
 
-    pub async fn peer_connected(ws: WebSocket, broker: Broker) {
-        tracing::debug!("user connected{:#?}", ws);
+    pub async fn peer_connected(
+        ws: WebSocketUpgrade,
+        headers: HeaderMap,
+        uri: Uri,
+        ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+        State((broker, upgrades)): State<(Broker, Upgrades)>,
+    ) -> Response {
+        let remote_addr = upgrades.client_addr(&headers, remote_addr);
+        if let Err(limited) = upgrades.limiter.upgrade(remote_addr.ip()) {
+            return super::too_many_requests(limited);
+        }
+        let user = match upgrades.authenticate(&headers, &uri) {
+            Ok(user) => user,
+            Err(error) => return super::unauthorized(error),
+        };
+        let Some(session) = upgrades.sessions.open() else {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        };
+        let ice = IceServers::new(&upgrades.server, super::requested_host(&headers));
+        let timeouts = upgrades.timeouts;
+        let limiter = upgrades.limiter.clone();
+        let info = PeerInfo::new(remote_addr, user);
+        ws.on_upgrade(move |socket| async move {
+            let _session = session;
+            tracing::debug!("user connected{:#?}", socket);
 
-        let mut peer = Peer::new(ws, broker.addr());
-        peer.register_at_broker();
-        peer.send_welcome().await;
-        peer.start().await;
+            let (sender, messages) = socket.split();
+            let mut peer = Peer::new(sender, messages, broker, timeouts, info, limiter);
+            if let Err(error) = peer.admit().await {
+                tracing::error!("failed to admit peer: {error}");
+                return;
+            }
+            peer.send_welcome(ice).await;
+            peer.start().await;
+        })
     }
 }
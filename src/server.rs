@@ -0,0 +1,153 @@
+use axum::{
+    response::{Html, Redirect},
+    routing::{delete, get, post},
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use hannibal::Service as _;
+use tokio::task::JoinHandle;
+use tower_http::services::ServeDir;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    actors, admin,
+    auth::{self, Authenticator},
+    basic,
+    broker::SignalingBroker as _,
+    keepalive::Timeouts,
+    rate_limit::RateLimiter,
+    relay, routes,
+    shutdown::Shutdown,
+    stun, tls, Backend, Config, IdGenerator,
+};
+
+/// The websocket, health and admin endpoints of the configured broker implementation.
+///
+/// Also returns the task that says goodbye to every peer on shutdown.
+async fn broker_routes(
+    config: &Config,
+    shutdown: Shutdown,
+    auth: Option<Arc<dyn Authenticator>>,
+) -> (Router, JoinHandle<()>) {
+    let admin_config = &config.server.admin;
+    let limiter = RateLimiter::new(&config.server.rate_limit);
+    let upgrades = routes::Upgrades {
+        server: Arc::new(config.server.clone()),
+        timeouts: Timeouts::from(&config.session),
+        sessions: shutdown.sessions(),
+        auth,
+        limiter: limiter.clone(),
+    };
+    tokio::spawn(limiter.prune_regularly());
+    let connect_timeout = Duration::from_secs(config.session.connect_timeout_secs);
+    let code_ttl = Duration::from_secs(config.session.pairing_code_ttl_secs);
+    let ids = IdGenerator::new(&config.server.peer_ids);
+    match config.server.backend {
+        Backend::Actors => {
+            let resume_grace = Duration::from_secs(config.session.resume_grace_secs);
+            if let Err(error) =
+                actors::Broker::configure(ids, resume_grace, connect_timeout, code_ttl).await
+            {
+                tracing::error!("failed to configure broker: {error}");
+            }
+            let drained = tokio::spawn(shutdown.on_signal(|reconnect_after| async move {
+                let broker = actors::Broker::from_registry().await;
+                broker.shutdown(reconnect_after).await
+            }));
+            let admin = Router::new()
+                .route("/admin/peers", get(admin::actors::peers))
+                .route("/admin/peers/{id}", delete(admin::actors::kick))
+                .route("/admin/sessions", get(admin::actors::sessions))
+                .route("/admin/broadcast", post(admin::actors::broadcast));
+            let routes = Router::new()
+                .route("/ws", get(routes::actors::peer_connected))
+                .route("/healthz", get(routes::actors::healthz))
+                .route("/readyz", get(routes::actors::readyz))
+                .route("/metrics", get(routes::actors::metrics))
+                .merge(admin::protect(admin, admin_config))
+                .with_state(upgrades);
+            (routes, drained)
+        }
+        Backend::Basic => {
+            let (broker, _broker_loop) = basic::Broker::create(ids, connect_timeout, code_ttl);
+            let goodbye = broker.clone();
+            let drained = tokio::spawn(shutdown.on_signal(|reconnect_after| async move {
+                goodbye.shutdown(reconnect_after).await
+            }));
+            let admin = Router::new()
+                .route("/admin/peers", get(admin::basic::peers))
+                .route("/admin/peers/{id}", delete(admin::basic::kick))
+                .route("/admin/sessions", get(admin::basic::sessions))
+                .route("/admin/broadcast", post(admin::basic::broadcast));
+            let routes = Router::new()
+                .route("/ws", get(routes::basic::peer_connected))
+                .route("/healthz", get(routes::basic::healthz))
+                .route("/readyz", get(routes::basic::readyz))
+                .route("/metrics", get(routes::basic::metrics))
+                .merge(admin::protect(admin, admin_config))
+                .with_state((broker, upgrades));
+            (routes, drained)
+        }
+    }
+}
+
+pub async fn serve(config: &Config) -> anyhow::Result<()> {
+    let handle = Handle::new();
+    let shutdown = Shutdown::new(handle.clone(), config.server.shutdown.clone());
+    let auth = auth::load(&config.server.auth).await?;
+    let (broker_routes, drained) = broker_routes(config, shutdown, auth).await;
+
+    let app = broker_routes
+        .route(
+            "/test",
+            get(|| async { Html(include_str!("../static/index.html")) }),
+        )
+        .nest_service("/app", ServeDir::new("./app/dist"))
+        .fallback(|| async { Redirect::permanent("/app/") });
+
+    if config.server.stun.enabled {
+        let stun_on = config.server.socket_addr(config.server.stun.port)?;
+        tokio::spawn(stun::serve(stun::bind(stun_on).await?));
+    }
+    // the relay stops when this is dropped
+    let _relay = if config.server.turn.enabled {
+        Some(relay::start(&config.server).await?)
+    } else {
+        None
+    };
+
+    let listen_on = config.server.socket_addr(config.server.port)?;
+    let tls = &config.server.tls;
+
+    if !tls.enabled {
+        tracing::info!(
+            backend = ?config.server.backend,
+            "listening on http://{}",
+            listen_on
+        );
+        axum_server::bind(listen_on)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+    } else {
+        let tls_config = RustlsConfig::from_config(Arc::new(tls::load(tls).await?));
+        let _watcher = tls::watch(tls_config.clone(), tls.clone());
+
+        tracing::info!(
+            backend = ?config.server.backend,
+            client_auth = tls.client_ca.is_some(),
+            "listening on https://{}",
+            listen_on
+        );
+        axum_server::tls_rustls::bind_rustls(listen_on, tls_config)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+    }
+
+    // the server only returns once shutdown has begun
+    drained.await?;
+    tracing::info!("shut down");
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Graceful shutdown on SIGINT or SIGTERM.
+//!
+//! New websockets are refused, the open ones are asked to come back later
+//! and given a while to close before the process exits.
+
+use axum_server::Handle;
+use tokio::sync::mpsc;
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{metrics::METRICS, ShutdownConfig};
+
+/// Resolves on the first SIGINT or SIGTERM.
+async fn signal() {
+    let interrupt = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            tracing::warn!("failed to listen for SIGINT: {error}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                tracing::warn!("failed to listen for SIGTERM: {error}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => tracing::info!("received SIGINT"),
+        _ = terminate => tracing::info!("received SIGTERM"),
+    }
+}
+
+/// Keeps track of open websockets, refuses new ones once shutdown has begun.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    open: mpsc::WeakSender<()>,
+    closing: Arc<AtomicBool>,
+}
+
+/// Held for as long as a websocket is open.
+#[derive(Debug)]
+pub struct Session {
+    _open: mpsc::Sender<()>,
+    opened: Instant,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        METRICS.websockets.dec();
+        METRICS.sessions.observe(self.opened.elapsed());
+    }
+}
+
+impl Sessions {
+    /// `None` if the server is shutting down.
+    pub fn open(&self) -> Option<Session> {
+        if self.is_closing() {
+            return None;
+        }
+        let open = self.open.upgrade()?;
+        METRICS.websockets.inc();
+        Some(Session {
+            _open: open,
+            opened: Instant::now(),
+        })
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Shutdown {
+    handle: Handle,
+    config: ShutdownConfig,
+    closing: Arc<AtomicBool>,
+    open: mpsc::Sender<()>,
+    closed: mpsc::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new(handle: Handle, config: ShutdownConfig) -> Self {
+        // nothing is ever sent, the channel closes with the last session
+        let (open, closed) = mpsc::channel(1);
+        Self {
+            handle,
+            config,
+            closing: Arc::new(AtomicBool::new(false)),
+            open,
+            closed,
+        }
+    }
+
+    pub fn sessions(&self) -> Sessions {
+        Sessions {
+            open: self.open.downgrade(),
+            closing: self.closing.clone(),
+        }
+    }
+
+    /// Wait for a signal, then have `goodbye` tell every peer and drain the open websockets.
+    ///
+    /// `goodbye` receives how long clients should wait before they reconnect.
+    pub async fn on_signal<G, F>(self, goodbye: G)
+    where
+        G: FnOnce(Duration) -> F,
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        signal().await;
+
+        let drain = Duration::from_secs(self.config.drain_timeout_secs);
+        tracing::info!("shutting down, waiting up to {drain:?} for websockets to close");
+        self.closing.store(true, Ordering::SeqCst);
+        self.handle.graceful_shutdown(Some(drain));
+
+        let reconnect_after = Duration::from_secs(self.config.reconnect_after_secs);
+        if let Err(error) = goodbye(reconnect_after).await {
+            tracing::warn!("failed to notify peers: {error}");
+        }
+
+        let Self {
+            open, mut closed, ..
+        } = self;
+        drop(open);
+        if tokio::time::timeout(drain, closed.recv()).await.is_err() {
+            tracing::warn!("websockets still open after {drain:?}, closing them anyway");
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Just enough STUN (RFC 5389) to let clients discover their reflexive address.
+
+use tokio::net::UdpSocket;
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+/// Answer a binding request with the address it came from, anything else is ignored.
+fn binding_response(request: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+    if request.len() < HEADER_LEN {
+        return None;
+    }
+    let kind = u16::from_be_bytes([request[0], request[1]]);
+    let length = u16::from_be_bytes([request[2], request[3]]) as usize;
+    let cookie = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+    if kind != BINDING_REQUEST || cookie != MAGIC_COOKIE || request.len() != HEADER_LEN + length {
+        return None;
+    }
+    let transaction = &request[8..HEADER_LEN];
+
+    let port = from.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut value = vec![0];
+    match from.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            value.push(0x01);
+            value.extend_from_slice(&port.to_be_bytes());
+            value.extend_from_slice(&(u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.push(0x02);
+            value.extend_from_slice(&port.to_be_bytes());
+            let cookie = MAGIC_COOKIE.to_be_bytes();
+            let key = cookie.iter().chain(transaction);
+            value.extend(ip.octets().iter().zip(key).map(|(byte, key)| byte ^ key));
+        }
+    }
+
+    let mut response = Vec::with_capacity(HEADER_LEN + 4 + value.len());
+    response.extend_from_slice(&BINDING_RESPONSE.to_be_bytes());
+    response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+    response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    response.extend_from_slice(transaction);
+    response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    response.extend_from_slice(&value);
+    Some(response)
+}
+
+pub async fn bind(listen_on: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(listen_on).await?;
+    tracing::info!("stun listening on udp://{}", listen_on);
+    Ok(socket)
+}
+
+pub async fn serve(socket: UdpSocket) {
+    let mut buffer = [0; 1500];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(error) => {
+                tracing::debug!("stun failed to receive: {error}");
+                continue;
+            }
+        };
+
+        let Some(response) = binding_response(&buffer[..len], from) else {
+            tracing::trace!("ignoring {len} bytes from {from}");
+            continue;
+        };
+        if let Err(error) = socket.send_to(&response, from).await {
+            tracing::debug!("stun failed to answer {from}: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 5769 2.1, a binding request with the usual ICE attributes
+    const REQUEST: &str = "
+        0001 0058 2112a442 b7e7a701bc34d686fa87dfae
+        8022 0010 5354554e2074657374 20636c69656e74
+        0024 0004 6e0001ff
+        8029 0008 932ff9b151263b36
+        0006 0009 6576746a3a68367659202020
+        0008 0014 9aeaa70cbfd8cb56781ef2b5b2d3f249c1b571a2
+        8028 0004 e57a3bcf";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let digits = hex.split_whitespace().collect::<String>();
+        (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn respond(request: &[u8], from: &str) -> Option<Vec<u8>> {
+        binding_response(request, from.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4() {
+        // RFC 5769 2.2, without the attributes that need a password
+        let expected = "
+            0101 000c 2112a442 b7e7a701bc34d686fa87dfae
+            0020 0008 0001a147 e112a643";
+        assert_eq!(
+            respond(&bytes(REQUEST), "192.0.2.1:32853"),
+            Some(bytes(expected))
+        );
+    }
+
+    #[test]
+    fn ipv6() {
+        // RFC 5769 2.3, without the attributes that need a password
+        let expected = "
+            0101 0018 2112a442 b7e7a701bc34d686fa87dfae
+            0020 0014 0002a147 0113a9faa5d3f179bc25f4b5bed2b9d9";
+        assert_eq!(
+            respond(
+                &bytes(REQUEST),
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            ),
+            Some(bytes(expected))
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let request = bytes(REQUEST);
+        assert_eq!(
+            respond(&request, "[::ffff:192.0.2.1]:32853"),
+            respond(&request, "192.0.2.1:32853")
+        );
+    }
+
+    #[test]
+    fn ignores_anything_else() {
+        let request = bytes(REQUEST);
+        let with = |index: usize, byte: u8| {
+            let mut request = request.clone();
+            request[index] = byte;
+            respond(&request, "192.0.2.1:32853")
+        };
+
+        // a binding indication and a binding response
+        assert_eq!(with(1, 0x11), None);
+        assert_eq!(with(0, 0x01), None);
+        // RFC 3489 has no magic cookie
+        assert_eq!(with(4, 0x00), None);
+        // the length disagrees with what was received
+        assert_eq!(with(3, 0x54), None);
+        assert_eq!(respond(&request[..100], "192.0.2.1:32853"), None);
+        // shorter than a header
+        assert_eq!(respond(&request[..HEADER_LEN - 1], "192.0.2.1:32853"), None);
+        assert_eq!(respond(&[], "192.0.2.1:32853"), None);
+    }
+}
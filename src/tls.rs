@@ -0,0 +1,104 @@
+use anyhow::Context as _;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio::task::JoinHandle;
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::TlsConfig;
+
+async fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let pem = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate in {}", path.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "no certificate in {}", path.display());
+    Ok(certs)
+}
+
+async fn read_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let pem = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .with_context(|| format!("invalid private key in {}", path.display()))?
+        .with_context(|| format!("no private key in {}", path.display()))
+}
+
+/// Build the rustls config from the files named in `tls`.
+pub async fn load(tls: &TlsConfig) -> anyhow::Result<ServerConfig> {
+    let certs = read_certs(&tls.cert).await?;
+    let key = read_key(&tls.key).await?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca).await? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Latest modification of any of the configured files.
+async fn last_modified(tls: &TlsConfig) -> Option<SystemTime> {
+    let mut latest = None;
+    let files = std::iter::once(&tls.cert)
+        .chain(Some(&tls.key))
+        .chain(tls.client_ca.as_ref());
+    for path in files {
+        let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        latest = latest.max(Some(modified));
+    }
+    latest
+}
+
+/// Reload the certificate whenever one of the files changes on disk.
+///
+/// A broken update is logged and the previous certificate stays in use.
+pub fn watch(rustls_config: RustlsConfig, tls: TlsConfig) -> Option<JoinHandle<()>> {
+    if tls.reload_secs == 0 {
+        return None;
+    }
+
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(tls.reload_secs));
+        let mut seen = last_modified(&tls).await;
+        loop {
+            interval.tick().await;
+            let modified = last_modified(&tls).await;
+            if modified.is_none() || modified == seen {
+                continue;
+            }
+            seen = modified;
+
+            match load(&tls).await {
+                Ok(config) => {
+                    rustls_config.reload_from_config(Arc::new(config));
+                    tracing::info!("reloaded certificate from {}", tls.cert.display());
+                }
+                Err(error) => {
+                    tracing::warn!("keeping previous certificate: {error:#}");
+                }
+            }
+        }
+    }))
+}
@@ -1,17 +1,360 @@
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use serde_json::Value;
+use std::{fmt, time::Duration};
 
-use crate::peer_id::PeerId;
+use crate::{
+    auth::Identity,
+    pairing_code::{ConnectTo, PairingCode},
+    peer_id::PeerId,
+    resume_token::ResumeToken,
+    room_id::RoomId,
+};
+
+/// bumped whenever a change to [`WsProtocol`] would break existing clients
+pub const PROTOCOL_VERSION: u32 = 2;
+/// oldest client version this server still talks to,
+/// 1 doesn't answer [`WsProtocol::ConnectRequest`] and could never be connected to
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+/// what a client speaks whose first message isn't a [`WsProtocol::Hello`]
+pub const UNANNOUNCED_VERSION: u32 = 1;
+const _: () = assert!(UNANNOUNCED_VERSION < MIN_PROTOCOL_VERSION);
 
 // websocket json protocol
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum WsProtocol {
-    Welcome(PeerId),
-    Connect(PeerId),
+    Welcome {
+        id: PeerId,
+        /// present this in a [`WsProtocol::Resume`] to take over this session after a drop
+        #[serde(skip_serializing_if = "Option::is_none")]
+        token: Option<ResumeToken>,
+        /// pass these on to `RTCPeerConnection`
+        #[serde(rename = "iceServers")]
+        ice_servers: Vec<IceServer>,
+        capabilities: Vec<Capability>,
+        version: u32,
+    },
+    /// first message of a client, answered with the negotiated version and capabilities
+    Hello {
+        version: u32,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
+    Resume(ResumeToken),
+    /// a peer id or a pairing code
+    Connect(ConnectTo),
+    /// asks for a pairing code that stands for the own id
+    RequestCode,
+    /// good for one [`WsProtocol::Connect`] within `expires_in_secs`
+    PairingCode {
+        code: PairingCode,
+        #[serde(rename = "expiresInSecs")]
+        expires_in_secs: u64,
+    },
+    /// sent to the peer that is connected to, which answers with accept or reject
+    ConnectRequest {
+        from: PeerId,
+        /// whom the requesting peer authenticated as
+        #[serde(
+            rename = "displayName",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        display_name: Option<String>,
+    },
+    /// the requesting peer may connect
+    Accept(PeerId),
+    Reject(PeerId),
     Connected(PeerId),
-    Subscribed,
-    Bye { reason: String },
+    /// follows [`WsProtocol::Connected`] if the correspondent authenticated
+    Identified {
+        peer: PeerId,
+        user: Identity,
+    },
+    Bye {
+        reason: String,
+        /// the server is going away, try again after this long
+        #[serde(
+            rename = "reconnectAfterSecs",
+            default,
+            skip_serializing_if = "Option::is_none"
+        )]
+        reconnect_after_secs: Option<u64>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// from the operators, to everyone
+    Notice {
+        message: String,
+    },
+
+    // rooms
+    Join(RoomId),
+    Joined {
+        room: RoomId,
+        members: Vec<PeerId>,
+    },
+    Leave,
+    PeerJoined {
+        room: RoomId,
+        peer: PeerId,
+    },
+    PeerLeft {
+        room: RoomId,
+        peer: PeerId,
+    },
+    /// addressed to a single member of the room
+    SendTo {
+        to: PeerId,
+        payload: Value,
+    },
+    /// stamped with the sender by the server
+    ReceivedFrom {
+        from: PeerId,
+        payload: Value,
+    },
+
+    // webrtc signaling
+    Offer(Signal),
+    Answer(Signal),
+    Candidate(Signal),
+}
+
+/// Machine readable reason for a [`WsProtocol::Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    UnknownPeer,
+    SelfConnect,
+    PeerBusy,
+    PeerGone,
+    /// the peer declined the connection
+    Rejected,
+    /// the peer didn't answer the connect request in time
+    TimedOut,
+    /// accept or reject without a pending request from that peer
+    NoRequest,
+    /// too many requests, or banned for asking for unknown peers
+    RateLimited,
+    ResumeFailed,
+    /// needs a [`Capability`] the client didn't offer in its hello
+    NotNegotiated,
+    Internal,
+}
+
+impl ErrorCode {
+    /// What the client is told, unless the error has more to say.
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorCode::UnknownPeer => "peer not found",
+            ErrorCode::SelfConnect => "attempted to connect to self",
+            ErrorCode::PeerBusy => "peer is already connected",
+            ErrorCode::PeerGone => "peer is no longer running",
+            ErrorCode::Rejected => "peer declined the connection",
+            ErrorCode::TimedOut => "peer did not answer in time",
+            ErrorCode::NoRequest => "no pending request from this peer",
+            ErrorCode::RateLimited => "too many requests",
+            ErrorCode::ResumeFailed => "session can not be resumed",
+            ErrorCode::NotNegotiated => "not negotiated in the hello",
+            ErrorCode::Internal => "internal error",
+        }
+    }
+}
+
+impl From<ErrorCode> for WsProtocol {
+    fn from(code: ErrorCode) -> Self {
+        WsProtocol::Error {
+            code,
+            message: code.message().to_string(),
+        }
+    }
+}
+
+/// A stun or turn server, shaped like an `RTCIceServer`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    /// unix timestamp after which the credential is rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// Optional parts of the protocol a server supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    /// [`WsProtocol::Resume`]
+    Resume,
+    /// [`WsProtocol::Join`] and friends
+    Rooms,
+    /// [`WsProtocol::Offer`], [`WsProtocol::Answer`] and [`WsProtocol::Candidate`]
+    Signaling,
+    /// binary frames are forwarded
+    Binary,
+    /// [`WsProtocol::RequestCode`]
+    PairingCodes,
+    /// offered by a newer client, ignored
+    #[serde(other)]
+    Unknown,
+}
+
+/// Signaling envelope, routed only to the addressed peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signal {
+    /// stamped by the server, whatever the client claims
+    #[serde(default)]
+    pub from: Option<PeerId>,
+    pub to: PeerId,
+    /// session description or ice candidate
+    pub payload: Value,
+}
+
+#[derive(Debug)]
+pub enum InvalidSignal {
+    NotASignal,
+    AddressedToSelf,
+    InvalidPayload(&'static str),
+}
+
+impl fmt::Display for InvalidSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidSignal::NotASignal => write!(f, "not a signaling message"),
+            InvalidSignal::AddressedToSelf => write!(f, "signal addressed to sender"),
+            InvalidSignal::InvalidPayload(kind) => write!(f, "invalid {kind} payload"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidSignal {}
+
+/// Why a client speaking `version` is turned away.
+pub fn unsupported_version(version: u32) -> String {
+    format!(
+        "protocol version {version} is no longer supported, please reload \
+         (server speaks {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION})"
+    )
+}
+
+fn check_description(payload: &Value, kind: &'static str) -> Result<(), InvalidSignal> {
+    let sdp_type = payload.get("type").and_then(Value::as_str);
+    let sdp = payload.get("sdp").and_then(Value::as_str);
+    match (sdp_type, sdp) {
+        (Some(sdp_type), Some(_)) if sdp_type == kind => Ok(()),
+        _ => Err(InvalidSignal::InvalidPayload(kind)),
+    }
+}
+
+fn check_candidate(payload: &Value) -> Result<(), InvalidSignal> {
+    // `null` marks the end of candidates
+    if payload.is_null() || payload.get("candidate").is_some_and(Value::is_string) {
+        Ok(())
+    } else {
+        Err(InvalidSignal::InvalidPayload("candidate"))
+    }
+}
+
+impl WsProtocol {
+    /// A [`WsProtocol::Bye`] without reconnect hint.
+    pub fn bye(reason: impl Into<String>) -> Self {
+        WsProtocol::Bye {
+            reason: reason.into(),
+            reconnect_after_secs: None,
+        }
+    }
+
+    /// The server is going away, the client should come back after `reconnect_after`.
+    pub fn shutting_down(reconnect_after: Duration) -> Self {
+        WsProtocol::Bye {
+            reason: String::from("server shutting down"),
+            reconnect_after_secs: Some(reconnect_after.as_secs()),
+        }
+    }
+
+    /// The capability a client has to negotiate before sending this.
+    pub fn requires(&self) -> Option<Capability> {
+        match self {
+            WsProtocol::Resume(_) => Some(Capability::Resume),
+            WsProtocol::RequestCode => Some(Capability::PairingCodes),
+            WsProtocol::Join(_) | WsProtocol::Leave | WsProtocol::SendTo { .. } => {
+                Some(Capability::Rooms)
+            }
+            WsProtocol::Offer(_) | WsProtocol::Answer(_) | WsProtocol::Candidate(_) => {
+                Some(Capability::Signaling)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_signal(&self) -> bool {
+        matches!(
+            self,
+            WsProtocol::Offer(_) | WsProtocol::Answer(_) | WsProtocol::Candidate(_)
+        )
+    }
+
+    /// Checks a signaling message and stamps it with the id of its sender.
+    ///
+    /// Returns the addressed recipient together with the stamped message.
+    pub fn stamp_signal(self, sender: &PeerId) -> Result<(PeerId, WsProtocol), InvalidSignal> {
+        let (signal, wrap): (Signal, fn(Signal) -> WsProtocol) = match self {
+            WsProtocol::Offer(signal) => {
+                check_description(&signal.payload, "offer")?;
+                (signal, WsProtocol::Offer)
+            }
+            WsProtocol::Answer(signal) => {
+                check_description(&signal.payload, "answer")?;
+                (signal, WsProtocol::Answer)
+            }
+            WsProtocol::Candidate(signal) => {
+                check_candidate(&signal.payload)?;
+                (signal, WsProtocol::Candidate)
+            }
+            _ => return Err(InvalidSignal::NotASignal),
+        };
+
+        if &signal.to == sender {
+            return Err(InvalidSignal::AddressedToSelf);
+        }
+
+        let to = signal.to.clone();
+        let stamped = Signal {
+            from: Some(sender.clone()),
+            ..signal
+        };
+        Ok((to, wrap(stamped)))
+    }
+
+    /// The answer to a client's [`WsProtocol::Hello`] along with the capabilities both sides
+    /// speak, or why we can't talk to it.
+    ///
+    /// A newer client is answered with our version and may decide to speak it.
+    pub fn answer_hello(
+        version: u32,
+        offered: &[Capability],
+        supported: &[Capability],
+    ) -> Result<(WsProtocol, Vec<Capability>), String> {
+        let version = version.min(PROTOCOL_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(unsupported_version(version));
+        }
+        let capabilities: Vec<_> = supported
+            .iter()
+            .filter(|capability| offered.contains(capability))
+            .copied()
+            .collect();
+        let hello = WsProtocol::Hello {
+            version,
+            capabilities: capabilities.clone(),
+        };
+        Ok((hello, capabilities))
+    }
 }
 
 impl fmt::Display for WsProtocol {
@@ -24,3 +367,123 @@ impl fmt::Display for WsProtocol {
 
 // pub type WsSender = SplitSink<WebSocket, Message>;
 // pub type WsReceiver = SplitStream<WebSocket>;
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn peer_id(id: &str) -> PeerId {
+        serde_json::from_value(id.into()).unwrap()
+    }
+
+    fn parse(message: Value) -> WsProtocol {
+        serde_json::from_value(message).unwrap()
+    }
+
+    #[test]
+    fn stamped_with_sender() {
+        let offer = parse(json!({"offer": {
+            "from": "mallory",
+            "to": "bob",
+            "payload": {"type": "offer", "sdp": "v=0"},
+        }}));
+        let (to, stamped) = offer.stamp_signal(&peer_id("alice")).unwrap();
+        assert_eq!(to, peer_id("bob"));
+        let WsProtocol::Offer(stamped) = stamped else {
+            panic!("not an offer anymore: {}", stamped);
+        };
+        assert_eq!(stamped.from, Some(peer_id("alice")));
+        assert_eq!(stamped.payload, json!({"type": "offer", "sdp": "v=0"}));
+    }
+
+    #[test]
+    fn descriptions() {
+        let answer = |payload| parse(json!({"answer": {"to": "bob", "payload": payload}}));
+        let alice = peer_id("alice");
+        assert!(answer(json!({"type": "answer", "sdp": "v=0"}))
+            .stamp_signal(&alice)
+            .is_ok());
+        assert!(matches!(
+            answer(json!({"type": "offer", "sdp": "v=0"})).stamp_signal(&alice),
+            Err(InvalidSignal::InvalidPayload("answer"))
+        ));
+        assert!(matches!(
+            answer(json!({"type": "answer"})).stamp_signal(&alice),
+            Err(InvalidSignal::InvalidPayload("answer"))
+        ));
+    }
+
+    #[test]
+    fn candidates() {
+        let candidate = |payload| parse(json!({"candidate": {"to": "bob", "payload": payload}}));
+        let alice = peer_id("alice");
+        let host = json!({"candidate": "candidate:1 1 udp 2130706431 192.0.2.1 4711 typ host"});
+        assert!(candidate(host).stamp_signal(&alice).is_ok());
+        // end of candidates
+        assert!(candidate(Value::Null).stamp_signal(&alice).is_ok());
+        assert!(matches!(
+            candidate(json!({"sdpMid": "0"})).stamp_signal(&alice),
+            Err(InvalidSignal::InvalidPayload("candidate"))
+        ));
+    }
+
+    #[test]
+    fn addressed_to_self() {
+        let offer = parse(json!({"offer": {
+            "to": "alice",
+            "payload": {"type": "offer", "sdp": "v=0"},
+        }}));
+        assert!(matches!(
+            offer.stamp_signal(&peer_id("alice")),
+            Err(InvalidSignal::AddressedToSelf)
+        ));
+    }
+
+    #[test]
+    fn newer_client_is_answered_with_our_version() {
+        let (hello, _) = WsProtocol::answer_hello(PROTOCOL_VERSION + 3, &[], &[]).unwrap();
+        assert!(matches!(hello, WsProtocol::Hello { version, .. } if version == PROTOCOL_VERSION));
+        let (hello, _) = WsProtocol::answer_hello(MIN_PROTOCOL_VERSION, &[], &[]).unwrap();
+        assert!(
+            matches!(hello, WsProtocol::Hello { version, .. } if version == MIN_PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn older_client_is_turned_away() {
+        let reason = WsProtocol::answer_hello(MIN_PROTOCOL_VERSION - 1, &[], &[]).unwrap_err();
+        assert_eq!(reason, unsupported_version(MIN_PROTOCOL_VERSION - 1));
+        assert!(WsProtocol::answer_hello(UNANNOUNCED_VERSION, &[], &[]).is_err());
+    }
+
+    #[test]
+    fn capabilities_both_speak() {
+        let hello = parse(json!({"hello": {
+            "version": PROTOCOL_VERSION,
+            "capabilities": ["rooms", "telepathy", "binary", "resume"],
+        }}));
+        let WsProtocol::Hello { capabilities, .. } = hello else {
+            panic!("not a hello: {}", hello);
+        };
+        // from a newer client
+        assert!(capabilities.contains(&Capability::Unknown));
+
+        let supported = [Capability::Rooms, Capability::Signaling, Capability::Binary];
+        let (hello, negotiated) =
+            WsProtocol::answer_hello(PROTOCOL_VERSION, &capabilities, &supported).unwrap();
+        assert_eq!(negotiated, vec![Capability::Rooms, Capability::Binary]);
+        assert!(
+            matches!(hello, WsProtocol::Hello { capabilities, .. } if capabilities == negotiated)
+        );
+    }
+
+    #[test]
+    fn not_a_signal() {
+        assert!(matches!(
+            WsProtocol::Leave.stamp_signal(&peer_id("alice")),
+            Err(InvalidSignal::NotASignal)
+        ));
+    }
+}